@@ -0,0 +1,109 @@
+//! With the `embed-models` feature, packs the models named by
+//! `RSPLEETER_EMBED_MODELS` into `$OUT_DIR/<name>.zip` and generates the table
+//! `src/embedded.rs` includes them with.
+use anyhow::{anyhow, bail, Context, Result};
+use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, Write},
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+/// Comma separated model names, e.g. `2stems,4stems`.
+const MODELS_ENV: &str = "RSPLEETER_EMBED_MODELS";
+/// Directory holding the models, defaults to `models` in the package.
+const MODELS_DIR_ENV: &str = "RSPLEETER_EMBED_MODELS_DIR";
+/// `deflate` (default) or `none`.
+const COMPRESSION_ENV: &str = "RSPLEETER_EMBED_COMPRESSION";
+
+fn main() -> Result<()> {
+    // Without any `rerun-if` line Cargo reruns the script on every change in
+    // the package.
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_EMBED_MODELS").is_none() {
+        return Ok(());
+    }
+    for name in [MODELS_ENV, MODELS_DIR_ENV, COMPRESSION_ENV] {
+        println!("cargo:rerun-if-env-changed={}", name);
+    }
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    let models_dir = match env::var(MODELS_DIR_ENV) {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from(env::var("CARGO_MANIFEST_DIR")?).join("models"),
+    };
+    let compression = match env::var(COMPRESSION_ENV).as_deref() {
+        Ok("deflate") | Err(_) => CompressionMethod::Deflated,
+        Ok("none") => CompressionMethod::Stored,
+        Ok(x) => bail!(
+            "Invalid {}, expected `deflate` or `none`: {}",
+            COMPRESSION_ENV,
+            x
+        ),
+    };
+    let names = env::var(MODELS_ENV)
+        .with_context(|| anyhow!("Set {} to the models to embed.", MODELS_ENV))?;
+
+    let mut table = String::from("&[\n");
+    for name in names.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let model_path = models_dir.join(name);
+        if !model_path.is_dir() {
+            bail!("Model to embed not found: {}", model_path);
+        }
+        let archive_path = out_dir.join(format!("{}.zip", name));
+        pack(&models_dir, name, &archive_path, compression)
+            .with_context(|| anyhow!("Pack {} failed.", model_path))?;
+        let digest = Sha256::digest(fs::read(&archive_path)?);
+        writeln!(
+            table,
+            "    EmbeddedModel {{ name: {:?}, sha256: \"{:x}\", archive: include_bytes!({:?}) }},",
+            name, digest, archive_path
+        )?;
+    }
+    table.push(']');
+    fs::write(out_dir.join("embedded_models.rs"), table)?;
+    Ok(())
+}
+
+/// Zip `<models_dir>/<name>` with entries relative to `models_dir`.
+fn pack(
+    models_dir: &Path,
+    name: &str,
+    archive_path: &Path,
+    compression: CompressionMethod,
+) -> Result<()> {
+    let options = FileOptions::default().compression_method(compression);
+    let mut archive = ZipWriter::new(File::create(archive_path)?);
+    // Entry names always use `/`.
+    let mut dirs = vec![name.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        println!("cargo:rerun-if-changed={}", models_dir.join(&dir));
+        archive.add_directory(dir.as_str(), options)?;
+        let mut entries = fs::read_dir(models_dir.join(&dir))?
+            .map(|entry| {
+                let file_name = entry?.file_name();
+                let file_name = file_name
+                    .to_str()
+                    .with_context(|| anyhow!("Non UTF-8 file name in {}", dir))?;
+                Ok(format!("{}/{}", dir, file_name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Sorted for reproducible archives.
+        entries.sort();
+        for path in entries {
+            let full_path = models_dir.join(&path);
+            if full_path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            println!("cargo:rerun-if-changed={}", full_path);
+            archive.start_file(path.as_str(), options)?;
+            io::copy(&mut File::open(&full_path)?, &mut archive)?;
+        }
+    }
+    archive.finish()?.flush()?;
+    Ok(())
+}
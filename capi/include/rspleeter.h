@@ -0,0 +1,105 @@
+#ifndef RSPLEETER_H
+#define RSPLEETER_H
+
+/* Generated by cbindgen from capi/src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Sample rate the models expect, in Hz.
+#define RSPLEETER_SAMPLE_RATE 44100
+
+// Channels the models expect, samples are interleaved.
+#define RSPLEETER_CHANNELS 2
+
+// Outcome of a call, the values match the exit codes of the CLI.
+typedef enum RspleeterStatus {
+  RSPLEETER_STATUS_OK = 0,
+  RSPLEETER_STATUS_UNKNOWN = 1,
+  // A null pointer, invalid UTF-8 or an unsupported value was passed.
+  RSPLEETER_STATUS_INVALID_ARGUMENT = 2,
+  RSPLEETER_STATUS_INPUT = 3,
+  RSPLEETER_STATUS_DECODE = 4,
+  RSPLEETER_STATUS_MODEL = 5,
+  RSPLEETER_STATUS_INFERENCE = 6,
+  RSPLEETER_STATUS_ENCODE = 7,
+  RSPLEETER_STATUS_IO = 8,
+  // A bug in rspleeter, the handle should not be used anymore.
+  RSPLEETER_STATUS_PANIC = 101,
+  RSPLEETER_STATUS_CANCELLED = 130,
+} RspleeterStatus;
+
+// A loaded model, reusable for any number of buffers. Calls on one handle
+// may run concurrently from several threads.
+typedef struct RspleeterSeparator RspleeterSeparator;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Message of the last failed call on this thread, null if none failed yet.
+//
+// The string is owned by the library and valid until the next failing call
+// on this thread.
+const char *rspleeter_last_error_message(void);
+
+// Load `model` (e.g. "2stems") from `models_dir` into `*separator`.
+//
+// # Safety
+//
+// `model` and `models_dir` are valid NUL-terminated strings, `separator` is
+// valid for writes. Free the handle with `rspleeter_separator_free`.
+RspleeterStatus rspleeter_separator_load(const char *model,
+                                         const char *models_dir,
+                                         RspleeterSeparator **separator);
+
+// Unload the model, null is ignored.
+//
+// # Safety
+//
+// `separator` is null or a handle from `rspleeter_separator_load` not freed
+// yet, and not in use by another thread.
+void rspleeter_separator_free(RspleeterSeparator *separator);
+
+// Number of stems the model produces, 0 for a null handle.
+//
+// # Safety
+//
+// `separator` is null or a valid handle.
+size_t rspleeter_separator_stem_count(const RspleeterSeparator *separator);
+
+// Name of stem `index` (e.g. "vocals"), null if out of range. The string
+// lives as long as the handle.
+//
+// # Safety
+//
+// `separator` is null or a valid handle.
+const char *rspleeter_separator_stem_name(const RspleeterSeparator *separator, size_t index);
+
+// Separate `frames` frames of interleaved stereo `samples` at
+// `RSPLEETER_SAMPLE_RATE` into `stems`.
+//
+// `stems` holds `rspleeter_separator_stem_count` pointers, each to a
+// caller-owned buffer of `frames * RSPLEETER_CHANNELS` floats receiving one
+// stem in the same layout as `samples`.
+//
+// # Safety
+//
+// `separator` is a valid handle, `samples` is valid for reads of
+// `frames * channels` floats and `stems` as described above. The buffers
+// must not overlap.
+RspleeterStatus rspleeter_separate(const RspleeterSeparator *separator,
+                                   const float *samples,
+                                   size_t frames,
+                                   uint32_t channels,
+                                   uint32_t sample_rate,
+                                   float *const *stems);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif // RSPLEETER_H
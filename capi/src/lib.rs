@@ -0,0 +1,292 @@
+//! C ABI, check `capi/include/rspleeter.h` for the generated header.
+//!
+//! Every function returns a [`RspleeterStatus`], details of the last failure
+//! on the calling thread are available from
+//! [`rspleeter_last_error_message`]. Panics are caught and reported as
+//! `RSPLEETER_STATUS_PANIC`, they never unwind into the host.
+use camino::Utf8Path as Path;
+use std::{
+    any::Any,
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    mem,
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+};
+
+use rspleeter::{
+    control::Control,
+    splitter::{Separator, SpleeterModelInfo},
+    Error,
+};
+
+/// Sample rate the models expect, in Hz.
+// A literal, cbindgen cannot evaluate `models::MODEL_SAMPLE_RATE`.
+pub const RSPLEETER_SAMPLE_RATE: u32 = 44100;
+/// Channels the models expect, samples are interleaved.
+pub const RSPLEETER_CHANNELS: u32 = 2;
+
+/// Outcome of a call, the values match the exit codes of the CLI.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RspleeterStatus {
+    Ok = 0,
+    Unknown = 1,
+    /// A null pointer, invalid UTF-8 or an unsupported value was passed.
+    InvalidArgument = 2,
+    Input = 3,
+    Decode = 4,
+    Model = 5,
+    Inference = 6,
+    Encode = 7,
+    Io = 8,
+    /// A bug in rspleeter, the handle should not be used anymore.
+    Panic = 101,
+    Cancelled = 130,
+}
+
+/// A loaded model, reusable for any number of buffers. Calls on one handle
+/// may run concurrently from several threads.
+pub struct RspleeterSeparator {
+    separator: Separator,
+    /// NUL-terminated stem names, in the order of the model outputs.
+    stem_names: Vec<CString>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+struct Failure {
+    status: RspleeterStatus,
+    message: String,
+}
+
+impl Failure {
+    fn invalid_argument(message: impl Into<String>) -> Self {
+        Self {
+            status: RspleeterStatus::InvalidArgument,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        let status = match e.exit_code() {
+            3 => RspleeterStatus::Input,
+            4 => RspleeterStatus::Decode,
+            5 => RspleeterStatus::Model,
+            6 => RspleeterStatus::Inference,
+            7 => RspleeterStatus::Encode,
+            8 => RspleeterStatus::Io,
+            130 => RspleeterStatus::Cancelled,
+            _ => RspleeterStatus::Unknown,
+        };
+        Self {
+            status,
+            // `{:#}` prints the whole chain of causes.
+            message: format!("{:#}", anyhow::Error::from(e)),
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Unknown panic."
+    }
+}
+
+fn set_last_error(message: &str) {
+    // Interior NULs would truncate the message anyway.
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+/// Run `f`, recording its failure or panic as the last error.
+fn ffi_call(f: impl FnOnce() -> Result<(), Failure>) -> RspleeterStatus {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => RspleeterStatus::Ok,
+        Ok(Err(failure)) => {
+            set_last_error(&failure.message);
+            failure.status
+        }
+        Err(payload) => {
+            set_last_error(&format!("Panicked: {}", panic_message(&*payload)));
+            RspleeterStatus::Panic
+        }
+    }
+}
+
+/// # Safety
+///
+/// `s` is null or a valid NUL-terminated string.
+unsafe fn to_str<'a>(s: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    if s.is_null() {
+        return Err(Failure::invalid_argument(format!("`{}` is null.", name)));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| Failure::invalid_argument(format!("`{}` is not UTF-8.", name)))
+}
+
+/// Message of the last failed call on this thread, null if none failed yet.
+///
+/// The string is owned by the library and valid until the next failing call
+/// on this thread.
+#[no_mangle]
+pub extern "C" fn rspleeter_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| match &*last_error.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Load `model` (e.g. "2stems") from `models_dir` into `*separator`.
+///
+/// # Safety
+///
+/// `model` and `models_dir` are valid NUL-terminated strings, `separator` is
+/// valid for writes. Free the handle with `rspleeter_separator_free`.
+#[no_mangle]
+pub unsafe extern "C" fn rspleeter_separator_load(
+    model: *const c_char,
+    models_dir: *const c_char,
+    separator: *mut *mut RspleeterSeparator,
+) -> RspleeterStatus {
+    ffi_call(|| {
+        if separator.is_null() {
+            return Err(Failure::invalid_argument("`separator` is null."));
+        }
+        let model = to_str(model, "model")?;
+        let models_dir = to_str(models_dir, "models_dir")?;
+        let model_info = SpleeterModelInfo::get_by_name(model)
+            .ok_or_else(|| Failure::invalid_argument(format!("Unknown model: {}", model)))?;
+        let handle = RspleeterSeparator {
+            separator: Separator::load(model_info, Path::new(models_dir))?,
+            // unwrap: stem names are literals without NUL.
+            stem_names: model_info
+                .track_names
+                .iter()
+                .map(|name| CString::new(*name).unwrap())
+                .collect(),
+        };
+        *separator = Box::into_raw(Box::new(handle));
+        Ok(())
+    })
+}
+
+/// Unload the model, null is ignored.
+///
+/// # Safety
+///
+/// `separator` is null or a handle from `rspleeter_separator_load` not freed
+/// yet, and not in use by another thread.
+#[no_mangle]
+pub unsafe extern "C" fn rspleeter_separator_free(separator: *mut RspleeterSeparator) {
+    if !separator.is_null() {
+        // Dropping TensorFlow objects is not expected to panic, but nothing
+        // may unwind from here.
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(separator))));
+    }
+}
+
+/// Number of stems the model produces, 0 for a null handle.
+///
+/// # Safety
+///
+/// `separator` is null or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn rspleeter_separator_stem_count(
+    separator: *const RspleeterSeparator,
+) -> usize {
+    match separator.as_ref() {
+        Some(separator) => separator.stem_names.len(),
+        None => 0,
+    }
+}
+
+/// Name of stem `index` (e.g. "vocals"), null if out of range. The string
+/// lives as long as the handle.
+///
+/// # Safety
+///
+/// `separator` is null or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn rspleeter_separator_stem_name(
+    separator: *const RspleeterSeparator,
+    index: usize,
+) -> *const c_char {
+    match separator.as_ref().and_then(|x| x.stem_names.get(index)) {
+        Some(name) => name.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Separate `frames` frames of interleaved stereo `samples` at
+/// `RSPLEETER_SAMPLE_RATE` into `stems`.
+///
+/// `stems` holds `rspleeter_separator_stem_count` pointers, each to a
+/// caller-owned buffer of `frames * RSPLEETER_CHANNELS` floats receiving one
+/// stem in the same layout as `samples`.
+///
+/// # Safety
+///
+/// `separator` is a valid handle, `samples` is valid for reads of
+/// `frames * channels` floats and `stems` as described above. The buffers
+/// must not overlap.
+#[no_mangle]
+pub unsafe extern "C" fn rspleeter_separate(
+    separator: *const RspleeterSeparator,
+    samples: *const f32,
+    frames: usize,
+    channels: u32,
+    sample_rate: u32,
+    stems: *const *mut f32,
+) -> RspleeterStatus {
+    ffi_call(|| {
+        let separator = separator
+            .as_ref()
+            .ok_or_else(|| Failure::invalid_argument("`separator` is null."))?;
+        if channels != RSPLEETER_CHANNELS {
+            return Err(Failure::invalid_argument(format!(
+                "Expected {} channels, got {}.",
+                RSPLEETER_CHANNELS, channels
+            )));
+        }
+        if sample_rate != RSPLEETER_SAMPLE_RATE {
+            return Err(Failure::invalid_argument(format!(
+                "Expected sample rate {}, got {}.",
+                RSPLEETER_SAMPLE_RATE, sample_rate
+            )));
+        }
+        if samples.is_null() || stems.is_null() {
+            return Err(Failure::invalid_argument("`samples` or `stems` is null."));
+        }
+        // `slice::from_raw_parts` takes at most `isize::MAX` bytes.
+        let len = frames
+            .checked_mul(channels as usize)
+            .filter(|len| *len <= isize::MAX as usize / mem::size_of::<f32>())
+            .ok_or_else(|| Failure::invalid_argument(format!("Too many frames: {}.", frames)))?;
+        let samples = slice::from_raw_parts(samples, len);
+        let stems = slice::from_raw_parts(stems, separator.stem_names.len());
+        if stems.iter().any(|stem| stem.is_null()) {
+            return Err(Failure::invalid_argument("A stem buffer is null."));
+        }
+
+        let separated = separator.separator.separate_interleaved(
+            samples,
+            channels as usize,
+            sample_rate as usize,
+            &Control::default(),
+        )?;
+        for (stem, separated) in stems.iter().zip(separated) {
+            slice::from_raw_parts_mut(*stem, len).copy_from_slice(&separated);
+        }
+        Ok(())
+    })
+}
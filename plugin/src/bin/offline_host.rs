@@ -0,0 +1,90 @@
+//! Render an audio file through the plugin engine without a DAW.
+use anyhow::{bail, Context, Result};
+use camino::Utf8PathBuf as PathBuf;
+use clap::Parser;
+use std::collections::HashMap;
+
+use rspleeter::{
+    config::Config,
+    control::Control,
+    decode,
+    encode::{self, EncodeOptions},
+    splitter::SpleeterModelInfo,
+    utils::{self, AudioData, AudioInfo},
+};
+use rspleeter_plugin::{
+    engine::{Engine, SAMPLE_RATE},
+    host,
+};
+
+#[derive(Parser)]
+#[clap(version, about)]
+struct Cli {
+    input: PathBuf,
+    output: PathBuf,
+    #[clap(long, default_value = "2stems")]
+    model: String,
+    /// Defaults to the configured models directory.
+    #[clap(long)]
+    models_dir: Option<PathBuf>,
+    /// Frames per block, as a host would use.
+    #[clap(long, default_value_t = 512)]
+    block_size: usize,
+    /// Gain of a stem in dB, e.g. `--gain vocals=-6`.
+    #[clap(long, value_parser = parse_gain)]
+    gain: Vec<(String, f32)>,
+    /// Silence a stem, e.g. `--mute vocals`.
+    #[clap(long)]
+    mute: Vec<String>,
+}
+
+fn parse_gain(s: &str) -> Result<(String, f32)> {
+    let (track_name, db) = match s.split_once('=') {
+        Some(x) => x,
+        None => bail!("Expected <stem>=<dB>: {}", s),
+    };
+    let db: f32 = db.parse().context("Invalid gain.")?;
+    Ok((track_name.to_owned(), 10f32.powf(db / 20.)))
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let model_info = match SpleeterModelInfo::get_by_name(&cli.model) {
+        Some(model_info) => model_info,
+        None => bail!("Unknown model: {}", cli.model),
+    };
+    let models_dir = match cli.models_dir {
+        Some(models_dir) => models_dir,
+        None => Config::load()?.models_dir,
+    };
+    let gains: HashMap<_, _> = cli.gain.into_iter().collect();
+
+    let pcm_audio_info = AudioInfo::new_pcm(SAMPLE_RATE);
+    let (audio_parameters, pcm_data) =
+        decode::decode_audio(&cli.input, &pcm_audio_info, false, &Control::default())?;
+    let audio_data = AudioData::from_pcm(&pcm_data, &pcm_audio_info);
+
+    let mut engine = Engine::new(models_dir, model_info, SAMPLE_RATE);
+    let samples = host::render(
+        &mut engine,
+        &audio_data.samples,
+        cli.block_size,
+        |track_name| {
+            if cli.mute.iter().any(|x| x == track_name) {
+                0.
+            } else {
+                gains.get(track_name).copied().unwrap_or(1.)
+            }
+        },
+    );
+
+    encode::encode_pcm_data(
+        &utils::samples_to_pcm(&samples),
+        &pcm_audio_info,
+        &audio_parameters,
+        &EncodeOptions::default(),
+        &Control::default(),
+        &cli.output,
+    )?;
+    Ok(())
+}
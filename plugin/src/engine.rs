@@ -0,0 +1,394 @@
+//! Real-time side of the plugin, independent of the plugin API.
+//!
+//! The audio thread only moves frames through lock-free ring buffers, models
+//! are loaded and run by a worker thread with a `StreamingSeparator`, which
+//! also resamples between the host and model rates. Output is delayed by a
+//! constant `latency()` frames, long enough for the worker to separate a
+//! segment while the next one is recorded.
+use camino::Utf8PathBuf as PathBuf;
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use rspleeter::{
+    models::MODEL_SAMPLE_RATE,
+    splitter::{Separator, SpleeterModelInfo},
+    streaming::StreamingSeparator,
+};
+
+use crate::resample::Resampler;
+
+/// Segment length in seconds, short to keep the latency usable.
+pub const SEGMENT_LENGTH: usize = 2;
+/// Context on both sides of a segment in seconds.
+pub const SEGMENT_OVERLAP: usize = 1;
+/// Sample rate of the models, other host rates are resampled to it.
+pub const SAMPLE_RATE: usize = MODEL_SAMPLE_RATE;
+
+/// Output delay in frames at `sample_rate`: the streaming latency plus one
+/// segment for the worker to separate it.
+pub fn latency(sample_rate: usize) -> usize {
+    (2 * SEGMENT_LENGTH + SEGMENT_OVERLAP) * sample_rate
+}
+/// Stems of the largest model.
+pub const MAX_STEMS: usize = 5;
+
+/// Stereo frame of every stem, unused stems are silent.
+type StemFrame = [[f32; 2]; MAX_STEMS];
+
+enum ToWorker {
+    Frame([f32; 2]),
+    /// Drop the stream so far and start a new one with this model, frames
+    /// sent before are discarded.
+    Reset(&'static SpleeterModelInfo),
+}
+
+enum FromWorker {
+    Frame(StemFrame),
+    /// Every following frame belongs to the stream started by the matching
+    /// `ToWorker::Reset`, `None` if the model failed to load.
+    Reset(Option<&'static SpleeterModelInfo>),
+}
+
+/// How long the worker waits for frames or free space.
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+struct Worker {
+    models_dir: PathBuf,
+    /// Host sample rate.
+    sample_rate: usize,
+    separators: HashMap<&'static str, Arc<Separator>>,
+    streaming: Option<StreamingSeparator>,
+    /// Host to model rate, `None` when they are the same.
+    input_resampler: Option<Resampler>,
+    /// Model to host rate of every stem, `None` when they are the same.
+    output_resampler: Option<Resampler>,
+    input: Consumer<ToWorker>,
+    output: Producer<FromWorker>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Worker {
+    fn load(&mut self, model_info: &'static SpleeterModelInfo) -> Option<StreamingSeparator> {
+        let separator = match self.separators.get(model_info.name) {
+            Some(separator) => separator.clone(),
+            None => match Separator::load(model_info, &self.models_dir) {
+                Ok(separator) => {
+                    let separator =
+                        Arc::new(separator.with_segmenting(SEGMENT_LENGTH, SEGMENT_OVERLAP));
+                    self.separators.insert(model_info.name, separator.clone());
+                    separator
+                }
+                Err(e) => {
+                    nih_plug::nih_error!("Load model {} failed: {:#}", model_info.name, e);
+                    return None;
+                }
+            },
+        };
+        Some(StreamingSeparator::new(separator, 2, SAMPLE_RATE))
+    }
+
+    /// Start resampling a new stream of `model_info`'s stems.
+    fn reset_resamplers(&mut self, model_info: &'static SpleeterModelInfo) {
+        let resample = self.sample_rate != SAMPLE_RATE;
+        self.input_resampler = resample.then(|| Resampler::new(self.sample_rate, SAMPLE_RATE, 2));
+        self.output_resampler = resample
+            .then(|| Resampler::new(SAMPLE_RATE, self.sample_rate, 2 * model_info.output_count));
+    }
+
+    /// Push `message`, waiting for free space. Returns false once stopped.
+    fn send(&mut self, mut message: FromWorker) -> bool {
+        loop {
+            match self.output.push(message) {
+                Ok(()) => return true,
+                Err(rtrb::PushError::Full(x)) => message = x,
+            }
+            if self.stop.load(Ordering::Relaxed) {
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn run(mut self) {
+        let mut block = Vec::new();
+        while !self.stop.load(Ordering::Relaxed) {
+            block.clear();
+            while let Ok(message) = self.input.pop() {
+                match message {
+                    ToWorker::Frame(frame) => block.extend(frame),
+                    ToWorker::Reset(model_info) => {
+                        block.clear();
+                        self.streaming = self.load(model_info);
+                        self.reset_resamplers(model_info);
+                        let loaded = self.streaming.as_ref().map(|_| model_info);
+                        if !self.send(FromWorker::Reset(loaded)) {
+                            return;
+                        }
+                    }
+                }
+            }
+            let streaming = match &mut self.streaming {
+                Some(streaming) if !block.is_empty() => streaming,
+                _ => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            let resampled;
+            let block = match &mut self.input_resampler {
+                Some(resampler) => {
+                    resampled = resampler.process(&block);
+                    &resampled
+                }
+                None => &block,
+            };
+            let stems = match streaming.push(block) {
+                Ok(stems) => stems,
+                Err(e) => {
+                    // Nothing is sent, the engine notices the underrun and
+                    // resets the stream.
+                    nih_plug::nih_error!("Separation failed: {:#}", e);
+                    let model_info = streaming.separator().model_info();
+                    streaming.reset();
+                    self.reset_resamplers(model_info);
+                    continue;
+                }
+            };
+            // Every stem's channels, frame by frame.
+            let frames = stems.first().map_or(0, |x| x.len() / 2);
+            let mut interleaved = Vec::with_capacity(frames * 2 * stems.len());
+            for i in 0..frames {
+                for stem in &stems {
+                    interleaved.extend_from_slice(&stem[2 * i..2 * i + 2]);
+                }
+            }
+            if let Some(resampler) = &mut self.output_resampler {
+                interleaved = resampler.process(&interleaved);
+            }
+            for stem_frames in interleaved.chunks(2 * stems.len()) {
+                let mut frame = StemFrame::default();
+                for (frame, stem) in frame.iter_mut().zip(stem_frames.chunks(2)) {
+                    *frame = [stem[0], stem[1]];
+                }
+                if !self.send(FromWorker::Frame(frame)) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Delays a stereo stream by `latency()` frames and replaces it by the mix
+/// of its stems.
+pub struct Engine {
+    /// Host sample rate.
+    sample_rate: usize,
+    to_worker: Producer<ToWorker>,
+    from_worker: Consumer<FromWorker>,
+    worker: Option<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+    /// Model of the current stream.
+    model_info: &'static SpleeterModelInfo,
+    /// Resets sent to the worker and not acknowledged yet, stem frames
+    /// received meanwhile are stale.
+    pending_resets: usize,
+    /// Model the worker loaded for the current stream.
+    loaded: Option<&'static SpleeterModelInfo>,
+    /// A reset to send at the start of the next block.
+    needs_reset: bool,
+    /// Frames output since the last reset.
+    position: usize,
+}
+
+impl Engine {
+    /// Start the worker for a host running at `sample_rate`, loading
+    /// `model_info` from `models_dir` in the background.
+    pub fn new(
+        models_dir: PathBuf,
+        model_info: &'static SpleeterModelInfo,
+        sample_rate: usize,
+    ) -> Self {
+        Self::spawn(models_dir, HashMap::new(), model_info, sample_rate)
+    }
+
+    /// Start the worker with `separator` already loaded for its model, e.g.
+    /// a `Separator::mock` in tests. Other models are looked up in the
+    /// current directory.
+    pub fn with_separator(separator: Separator, sample_rate: usize) -> Self {
+        let model_info = separator.model_info();
+        let separator = separator.with_segmenting(SEGMENT_LENGTH, SEGMENT_OVERLAP);
+        let separators = HashMap::from([(model_info.name, Arc::new(separator))]);
+        Self::spawn(PathBuf::new(), separators, model_info, sample_rate)
+    }
+
+    fn spawn(
+        models_dir: PathBuf,
+        separators: HashMap<&'static str, Arc<Separator>>,
+        model_info: &'static SpleeterModelInfo,
+        sample_rate: usize,
+    ) -> Self {
+        // Room for the worker to load a model while frames keep coming.
+        let (to_worker, input) = RingBuffer::new(4 * latency(sample_rate));
+        let (output, from_worker) = RingBuffer::new(2 * latency(sample_rate));
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = Worker {
+            models_dir,
+            sample_rate,
+            separators,
+            streaming: None,
+            input_resampler: None,
+            output_resampler: None,
+            input,
+            output,
+            stop: stop.clone(),
+        };
+        let worker = thread::Builder::new()
+            .name("rspleeter-worker".to_owned())
+            .spawn(move || worker.run())
+            .expect("Spawn worker thread failed.");
+        Self {
+            sample_rate,
+            to_worker,
+            from_worker,
+            worker: Some(worker),
+            stop,
+            model_info,
+            pending_resets: 0,
+            loaded: None,
+            needs_reset: true,
+            position: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    /// Output delay in frames.
+    pub fn latency(&self) -> usize {
+        latency(self.sample_rate)
+    }
+
+    /// Switch to `model_info` at the next block, restarting the stream.
+    pub fn set_model(&mut self, model_info: &'static SpleeterModelInfo) {
+        if model_info.name != self.model_info.name {
+            self.model_info = model_info;
+            self.needs_reset = true;
+        }
+    }
+
+    /// Restart the stream at the next block, e.g. when the transport jumps.
+    pub fn reset(&mut self) {
+        self.needs_reset = true;
+    }
+
+    fn send_reset(&mut self) {
+        if self
+            .to_worker
+            .push(ToWorker::Reset(self.model_info))
+            .is_ok()
+        {
+            self.pending_resets += 1;
+            self.needs_reset = false;
+            self.position = 0;
+        }
+    }
+
+    /// Next stem frame of the current stream, `None` when not available.
+    /// `wait` blocks until it is, for offline rendering.
+    fn next_frame(&mut self, wait: bool) -> Option<StemFrame> {
+        loop {
+            if self.pending_resets == 0 && self.loaded.is_none() {
+                return None;
+            }
+            match self.from_worker.pop() {
+                Ok(FromWorker::Reset(loaded)) => {
+                    self.pending_resets -= 1;
+                    self.loaded = loaded;
+                }
+                Ok(FromWorker::Frame(frame)) if self.pending_resets == 0 => return Some(frame),
+                Ok(FromWorker::Frame(_)) => {}
+                Err(_) => {
+                    let worker_alive = self.worker.as_ref().map_or(false, |x| !x.is_finished());
+                    if !wait || !worker_alive {
+                        return None;
+                    }
+                    thread::sleep(Duration::from_micros(100));
+                }
+            }
+        }
+    }
+
+    /// Replace `left` and `right` by the mix of their stems `latency()`
+    /// frames earlier, each stem scaled by `gain(track_name)` once per frame.
+    ///
+    /// With `offline`, waits for the worker instead of outputting silence
+    /// when it falls behind, so a render is complete whatever the speed.
+    pub fn process(
+        &mut self,
+        left: &mut [f32],
+        right: &mut [f32],
+        offline: bool,
+        mut gain: impl FnMut(&'static str) -> f32,
+    ) {
+        if self.needs_reset {
+            self.send_reset();
+        }
+        if !self.needs_reset {
+            for (left, right) in left.iter().zip(right.iter()) {
+                if self
+                    .to_worker
+                    .push(ToWorker::Frame([*left, *right]))
+                    .is_err()
+                {
+                    // The worker is stuck, e.g. loading a model, resync.
+                    self.needs_reset = true;
+                    break;
+                }
+            }
+        }
+
+        for (left, right) in left.iter_mut().zip(right.iter_mut()) {
+            // The worker's frames are already the streaming latency behind.
+            let frame = if self.needs_reset || self.position < SEGMENT_LENGTH * self.sample_rate {
+                None
+            } else {
+                let frame = self.next_frame(offline);
+                if frame.is_none() && self.loaded.is_some() && self.pending_resets == 0 {
+                    // Underrun, resync at the next block.
+                    self.needs_reset = true;
+                }
+                frame
+            };
+            self.position += 1;
+
+            let (mut mix_left, mut mix_right) = (0., 0.);
+            if let (Some(frame), Some(model_info)) = (frame, self.loaded) {
+                for (stem, track_name) in frame.iter().zip(&model_info.track_names) {
+                    let gain = gain(*track_name);
+                    mix_left += gain * stem[0];
+                    mix_right += gain * stem[1];
+                }
+            }
+            *left = mix_left;
+            *right = mix_right;
+        }
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! Headless offline host, drives an `Engine` block by block like a DAW
+//! rendering with latency compensation.
+use std::iter;
+
+use crate::engine::Engine;
+
+/// Render interleaved stereo `samples` through `engine` in blocks of
+/// `block_size` frames, returning the interleaved output aligned with the
+/// input.
+pub fn render(
+    engine: &mut Engine,
+    samples: &[f32],
+    block_size: usize,
+    mut gain: impl FnMut(&'static str) -> f32,
+) -> Vec<f32> {
+    // Silence after the input flushes the latency.
+    let latency = engine.latency();
+    let channel = |offset: usize| -> Vec<f32> {
+        samples
+            .iter()
+            .skip(offset)
+            .step_by(2)
+            .copied()
+            .chain(iter::repeat(0.).take(latency))
+            .collect()
+    };
+    let (mut left, mut right) = (channel(0), channel(1));
+    for (left, right) in left
+        .chunks_mut(block_size.max(1))
+        .zip(right.chunks_mut(block_size.max(1)))
+    {
+        engine.process(left, right, true, &mut gain);
+    }
+    left[latency..]
+        .iter()
+        .zip(&right[latency..])
+        .flat_map(|(left, right)| [*left, *right])
+        .collect()
+}
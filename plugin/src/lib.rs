@@ -0,0 +1,222 @@
+//! CLAP plugin isolating vocals and instruments, check `plugin/README.md`.
+use nih_plug::prelude::*;
+use std::sync::Arc;
+
+use rspleeter::{config::Config, splitter::SpleeterModelInfo};
+
+pub mod engine;
+pub mod host;
+mod resample;
+
+use engine::Engine;
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    #[id = "2stems"]
+    #[name = "2 stems"]
+    TwoStems,
+    #[id = "4stems"]
+    #[name = "4 stems"]
+    FourStems,
+    #[id = "5stems"]
+    #[name = "5 stems"]
+    FiveStems,
+}
+
+impl Model {
+    pub fn info(self) -> &'static SpleeterModelInfo {
+        let name = match self {
+            Model::TwoStems => "2stems",
+            Model::FourStems => "4stems",
+            Model::FiveStems => "5stems",
+        };
+        // unwrap: built-in models.
+        SpleeterModelInfo::get_by_name(name).unwrap()
+    }
+}
+
+#[derive(Params)]
+pub struct StemParams {
+    #[id = "gain"]
+    pub gain: FloatParam,
+    #[id = "mute"]
+    pub mute: BoolParam,
+}
+
+impl Default for StemParams {
+    fn default() -> Self {
+        Self {
+            gain: FloatParam::new(
+                "Gain",
+                util::db_to_gain(0.0),
+                FloatRange::Skewed {
+                    min: util::db_to_gain(-30.0),
+                    max: util::db_to_gain(12.0),
+                    factor: FloatRange::gain_skew_factor(-30.0, 12.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_unit(" dB")
+            .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
+            .with_string_to_value(formatters::s2v_f32_gain_to_db()),
+            mute: BoolParam::new("Mute", false),
+        }
+    }
+}
+
+impl StemParams {
+    /// Gain of the next sample, advancing the smoother.
+    fn next_gain(&self) -> f32 {
+        let gain = self.gain.smoothed.next();
+        if self.mute.value() {
+            0.0
+        } else {
+            gain
+        }
+    }
+}
+
+/// Stems of every model have a gain and a mute, those the current model
+/// lacks are ignored.
+#[derive(Params)]
+pub struct RspleeterParams {
+    #[id = "model"]
+    pub model: EnumParam<Model>,
+    #[nested(id_prefix = "vocals", group = "Vocals")]
+    pub vocals: StemParams,
+    #[nested(id_prefix = "accompaniment", group = "Accompaniment")]
+    pub accompaniment: StemParams,
+    #[nested(id_prefix = "drums", group = "Drums")]
+    pub drums: StemParams,
+    #[nested(id_prefix = "bass", group = "Bass")]
+    pub bass: StemParams,
+    #[nested(id_prefix = "piano", group = "Piano")]
+    pub piano: StemParams,
+    #[nested(id_prefix = "other", group = "Other")]
+    pub other: StemParams,
+}
+
+impl Default for RspleeterParams {
+    fn default() -> Self {
+        Self {
+            model: EnumParam::new("Model", Model::TwoStems),
+            vocals: StemParams::default(),
+            accompaniment: StemParams::default(),
+            drums: StemParams::default(),
+            bass: StemParams::default(),
+            piano: StemParams::default(),
+            other: StemParams::default(),
+        }
+    }
+}
+
+impl RspleeterParams {
+    pub fn stem(&self, track_name: &str) -> &StemParams {
+        match track_name {
+            "vocals" => &self.vocals,
+            "accompaniment" => &self.accompaniment,
+            "drums" => &self.drums,
+            "bass" => &self.bass,
+            "piano" => &self.piano,
+            _ => &self.other,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Rspleeter {
+    params: Arc<RspleeterParams>,
+    /// Created on the first activation, kept with its loaded models across
+    /// reactivations.
+    engine: Option<Engine>,
+    offline: bool,
+}
+
+impl Plugin for Rspleeter {
+    const NAME: &'static str = "rspleeter";
+    const VENDOR: &'static str = "rspleeter";
+    const URL: &'static str = "https://github.com/ldm0/rspleeter";
+    const EMAIL: &'static str = "";
+    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
+        main_input_channels: NonZeroU32::new(2),
+        main_output_channels: NonZeroU32::new(2),
+        ..AudioIOLayout::const_default()
+    }];
+
+    type SysExMessage = ();
+    type BackgroundTask = ();
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    fn initialize(
+        &mut self,
+        _audio_io_layout: &AudioIOLayout,
+        buffer_config: &BufferConfig,
+        context: &mut impl InitContext<Self>,
+    ) -> bool {
+        let sample_rate = buffer_config.sample_rate as usize;
+        self.offline = buffer_config.process_mode == ProcessMode::Offline;
+        context.set_latency_samples(engine::latency(sample_rate) as u32);
+
+        if self
+            .engine
+            .as_ref()
+            .map_or(true, |engine| engine.sample_rate() != sample_rate)
+        {
+            let config = Config::load().unwrap_or_else(|e| {
+                nih_error!("Load config failed, using defaults: {:#}", e);
+                Config::default()
+            });
+            self.engine = Some(Engine::new(
+                config.models_dir,
+                self.params.model.value().info(),
+                sample_rate,
+            ));
+        }
+        true
+    }
+
+    fn reset(&mut self) {
+        if let Some(engine) = &mut self.engine {
+            engine.reset();
+        }
+    }
+
+    fn process(
+        &mut self,
+        buffer: &mut Buffer,
+        _aux: &mut AuxiliaryBuffers,
+        _context: &mut impl ProcessContext<Self>,
+    ) -> ProcessStatus {
+        let engine = match &mut self.engine {
+            Some(engine) => engine,
+            None => return ProcessStatus::Normal,
+        };
+        engine.set_model(self.params.model.value().info());
+        let params = &self.params;
+        let (left, right) = buffer.as_slice().split_at_mut(1);
+        engine.process(left[0], right[0], self.offline, |track_name| {
+            params.stem(track_name).next_gain()
+        });
+        ProcessStatus::Normal
+    }
+}
+
+impl ClapPlugin for Rspleeter {
+    const CLAP_ID: &'static str = "io.github.ldm0.rspleeter";
+    const CLAP_DESCRIPTION: Option<&'static str> =
+        Some("Vocal and instrument isolation with Spleeter models");
+    const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
+    const CLAP_SUPPORT_URL: Option<&'static str> = None;
+    const CLAP_FEATURES: &'static [ClapFeature] = &[
+        ClapFeature::AudioEffect,
+        ClapFeature::Stereo,
+        ClapFeature::Utility,
+    ];
+}
+
+nih_export_clap!(Rspleeter);
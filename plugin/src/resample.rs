@@ -0,0 +1,123 @@
+//! Streaming resampling between the host and model sample rates.
+use std::f64::consts::PI;
+
+/// Zero crossings of the windowed sinc on each side of a frame.
+const HALF_TAPS: usize = 16;
+
+/// Windowed-sinc resampler of interleaved frames.
+///
+/// Output frame `j` is the input at time `j * from / to`, so the signal is
+/// not delayed, but a frame is only returned once the `HALF_TAPS` input
+/// frames after it are pushed.
+pub struct Resampler {
+    nb_channels: usize,
+    /// Input frames per output frame.
+    step: f64,
+    /// Cutoff relative to the input Nyquist frequency, below both rates.
+    cutoff: f64,
+    /// Interleaved input from frame `start` on, frame 0 is `HALF_TAPS` frames
+    /// of silence before the stream.
+    input: Vec<f32>,
+    start: usize,
+    /// Next output frame.
+    position: usize,
+}
+
+impl Resampler {
+    pub fn new(from: usize, to: usize, nb_channels: usize) -> Self {
+        Self {
+            nb_channels,
+            step: from as f64 / to as f64,
+            cutoff: 0.95 * (to as f64 / from as f64).min(1.),
+            input: vec![0.; HALF_TAPS * nb_channels],
+            start: 0,
+            position: 0,
+        }
+    }
+
+    /// Input time of the next output frame, in frames from the padding.
+    fn time(&self) -> f64 {
+        self.position as f64 * self.step + HALF_TAPS as f64
+    }
+
+    fn kernel(&self, x: f64) -> f64 {
+        let sinc = if x == 0. {
+            self.cutoff
+        } else {
+            (PI * self.cutoff * x).sin() / (PI * x)
+        };
+        // Hann window over the taps.
+        sinc * (0.5 + 0.5 * (PI * x / HALF_TAPS as f64).cos())
+    }
+
+    /// Append interleaved `input`, returning the output frames completed by
+    /// it.
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        let nb_channels = self.nb_channels;
+        self.input.extend_from_slice(input);
+        let received = self.start + self.input.len() / nb_channels;
+        let mut output = Vec::new();
+        let mut sum = vec![0f64; nb_channels];
+        loop {
+            let time = self.time();
+            let center = time as usize;
+            if center + HALF_TAPS >= received {
+                break;
+            }
+            sum.fill(0.);
+            for i in center + 1 - HALF_TAPS..=center + HALF_TAPS {
+                let weight = self.kernel(time - i as f64);
+                let frame = &self.input[(i - self.start) * nb_channels..][..nb_channels];
+                for (sum, x) in sum.iter_mut().zip(frame) {
+                    *sum += weight * *x as f64;
+                }
+            }
+            output.extend(sum.iter().map(|x| *x as f32));
+            self.position += 1;
+        }
+        // Keep the frames before the next output frame's taps.
+        let keep_from = (self.time() as usize + 1).saturating_sub(HALF_TAPS);
+        if keep_from > self.start {
+            self.input.drain(..(keep_from - self.start) * nb_channels);
+            self.start = keep_from;
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frames: usize, rate: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (2. * PI * 440. * i as f64 / rate as f64).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_keeps_timing() {
+        let input = sine(48000, 48000);
+        let mut down = Resampler::new(48000, 44100, 1);
+        let mut up = Resampler::new(44100, 48000, 1);
+        let mut output = Vec::new();
+        for block in input.chunks(999) {
+            output.extend(up.process(&down.process(block)));
+        }
+        // Both resamplers hold back frames, but nothing is delayed.
+        assert!(output.len() > 47900);
+        for (x, y) in output.iter().zip(&input).skip(100) {
+            assert!((x - y).abs() < 1e-2, "{} != {}", x, y);
+        }
+    }
+
+    #[test]
+    fn same_rate_is_identity() {
+        let input = sine(1000, 44100);
+        let output = Resampler::new(44100, 44100, 1).process(&input);
+        assert_eq!(output.len(), 1000 - HALF_TAPS);
+        for (x, y) in output.iter().zip(&input) {
+            assert!((x - y).abs() < 1e-2);
+        }
+    }
+}
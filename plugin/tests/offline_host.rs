@@ -0,0 +1,70 @@
+use rspleeter::{
+    control::Control,
+    mock::MockBehavior,
+    splitter::{Separator, SpleeterModelInfo},
+};
+use rspleeter_plugin::{
+    engine::{self, Engine, SAMPLE_RATE, SEGMENT_LENGTH, SEGMENT_OVERLAP},
+    host,
+};
+
+/// Stems that differ, so a mix-up of their gains shows.
+fn mock() -> Separator {
+    Separator::mock(
+        SpleeterModelInfo::get_by_name("2stems").unwrap(),
+        MockBehavior::Bands(vec![500.]),
+    )
+}
+
+fn signal(frames: usize) -> Vec<f32> {
+    (0..frames)
+        .flat_map(|i| {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            let x = (2. * std::f32::consts::PI * 440. * t).sin() / 2.;
+            [x, -x]
+        })
+        .collect()
+}
+
+#[test]
+fn missing_model_renders_silence() {
+    let model_info = SpleeterModelInfo::get_by_name("2stems").unwrap();
+    for sample_rate in [SAMPLE_RATE, 48000] {
+        let mut engine = Engine::new("does-not-exist".into(), model_info, sample_rate);
+        let samples = signal(SAMPLE_RATE / 2);
+        let output = host::render(&mut engine, &samples, 512, |_| 1.);
+        assert_eq!(output.len(), samples.len());
+        assert!(output.iter().all(|x| *x == 0.));
+    }
+}
+
+#[test]
+fn engine_matches_offline() {
+    let samples = signal(SAMPLE_RATE * 5 + 777);
+
+    let mut engine = Engine::with_separator(mock(), SAMPLE_RATE);
+    let output = host::render(&mut engine, &samples, 333, |track_name| match track_name {
+        "vocals" => 1.,
+        _ => 0.5,
+    });
+
+    // The host feeds `latency()` frames of silence after the input.
+    let mut padded = samples.clone();
+    padded.resize(samples.len() + 2 * engine::latency(SAMPLE_RATE), 0.);
+    let separator = mock().with_segmenting(SEGMENT_LENGTH, SEGMENT_OVERLAP);
+    let stems = separator
+        .separate_interleaved(&padded, 2, SAMPLE_RATE, &Control::default())
+        .unwrap();
+
+    assert_eq!(output.len(), samples.len());
+    for (i, x) in output.iter().enumerate() {
+        let expected = stems[0][i] + 0.5 * stems[1][i];
+        assert!(
+            (x - expected).abs() <= 1e-5,
+            "frame {}: {} != {}",
+            i / 2,
+            x,
+            expected
+        );
+    }
+}
@@ -0,0 +1,27 @@
+from os import PathLike
+from typing import Dict, List, Optional, Union
+
+import numpy as np
+import numpy.typing as npt
+
+_Path = Union[str, PathLike]
+
+class Separator:
+    def __init__(self, model: str = "2stems", models_dir: Optional[_Path] = None) -> None: ...
+    @property
+    def stems(self) -> List[str]: ...
+    def separate(
+        self, waveform: npt.NDArray[np.float32], sample_rate: int
+    ) -> Dict[str, npt.NDArray[np.float32]]: ...
+    def separate_file(
+        self, input: _Path, output_dir: _Path, format: Optional[str] = None
+    ) -> Dict[str, str]: ...
+
+def separate_file(
+    input: _Path,
+    output_dir: _Path,
+    model: str = "2stems",
+    models_dir: Optional[_Path] = None,
+    format: Optional[str] = None,
+) -> Dict[str, str]: ...
+def available_models(models_dir: Optional[_Path] = None) -> List[str]: ...
@@ -0,0 +1,198 @@
+//! Python bindings, built with maturin, check `python/README.md`.
+use camino::Utf8PathBuf as PathBuf;
+use numpy::{IntoPyArray, PyReadonlyArray2};
+use pyo3::{
+    exceptions::{PyKeyboardInterrupt, PyOSError, PyRuntimeError, PyValueError},
+    prelude::*,
+    types::PyDict,
+};
+use std::borrow::Cow;
+
+use rspleeter::{
+    config::Config,
+    control::Control,
+    encode::EncodeOptions,
+    models::MODEL_SAMPLE_RATE,
+    output::OverwritePolicy,
+    splitter::{self, SpleeterModelInfo},
+    Error,
+};
+
+/// Channels the models are trained on.
+const NB_CHANNELS: usize = 2;
+
+fn to_py_err(e: Error) -> PyErr {
+    let exception: fn(String) -> PyErr = match e {
+        Error::Input(_) => PyValueError::new_err,
+        Error::Io(_) => PyOSError::new_err,
+        Error::Cancelled => PyKeyboardInterrupt::new_err,
+        _ => PyRuntimeError::new_err,
+    };
+    // `{:#}` prints the whole chain of causes.
+    exception(format!("{:#}", anyhow::Error::from(e)))
+}
+
+fn utf8_path(path: std::path::PathBuf) -> PyResult<PathBuf> {
+    PathBuf::from_path_buf(path)
+        .map_err(|path| PyValueError::new_err(format!("Path is not UTF-8: {}", path.display())))
+}
+
+/// `models_dir`, the configured one (as for the CLI) if `None`.
+fn models_dir(models_dir: Option<std::path::PathBuf>) -> PyResult<PathBuf> {
+    match models_dir {
+        Some(models_dir) => utf8_path(models_dir),
+        None => Config::load()
+            .map(|config| config.models_dir)
+            .map_err(|e| PyValueError::new_err(format!("{:#}", e))),
+    }
+}
+
+fn model_info(model: &str) -> PyResult<&'static SpleeterModelInfo> {
+    SpleeterModelInfo::get_by_name(model).ok_or_else(|| {
+        PyValueError::new_err(format!(
+            "Unknown model: {}, available: {}",
+            model,
+            SpleeterModelInfo::all()
+                .map(|x| x.name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    })
+}
+
+/// A loaded model, reusable for any number of inputs.
+#[pyclass(frozen)]
+struct Separator {
+    inner: splitter::Separator,
+}
+
+#[pymethods]
+impl Separator {
+    #[new]
+    #[pyo3(signature = (model = "2stems", models_dir = None))]
+    fn new(py: Python<'_>, model: &str, models_dir: Option<std::path::PathBuf>) -> PyResult<Self> {
+        let model_info = model_info(model)?;
+        let models_dir = self::models_dir(models_dir)?;
+        let inner = py
+            .allow_threads(|| splitter::Separator::load(model_info, &models_dir))
+            .map_err(to_py_err)?;
+        Ok(Self { inner })
+    }
+
+    /// Stem names, in the order of the model outputs.
+    #[getter]
+    fn stems(&self) -> Vec<&'static str> {
+        self.inner.model_info().track_names.clone()
+    }
+
+    /// Separate `waveform` of shape (samples, channels), mono or stereo
+    /// float32 at 44.1kHz, into a stereo waveform per stem.
+    ///
+    /// C-contiguous stereo input is used without copying.
+    fn separate<'py>(
+        &self,
+        py: Python<'py>,
+        waveform: PyReadonlyArray2<'py, f32>,
+        sample_rate: usize,
+    ) -> PyResult<&'py PyDict> {
+        if sample_rate != MODEL_SAMPLE_RATE {
+            return Err(PyValueError::new_err(format!(
+                "Sample rate must be {}, got {}",
+                MODEL_SAMPLE_RATE, sample_rate
+            )));
+        }
+        let samples: Cow<[f32]> = match (waveform.shape()[1], waveform.as_slice()) {
+            (NB_CHANNELS, Ok(samples)) => Cow::Borrowed(samples),
+            (NB_CHANNELS, Err(_)) => Cow::Owned(waveform.as_array().iter().copied().collect()),
+            (1, _) => Cow::Owned(waveform.as_array().iter().flat_map(|&x| [x, x]).collect()),
+            (channels, _) => {
+                return Err(PyValueError::new_err(format!(
+                    "Expected 1 or 2 channels, got {}",
+                    channels
+                )))
+            }
+        };
+
+        let stems = py
+            .allow_threads(|| {
+                self.inner.separate_interleaved(
+                    &samples,
+                    NB_CHANNELS,
+                    sample_rate,
+                    &Control::default(),
+                )
+            })
+            .map_err(to_py_err)?;
+
+        let dict = PyDict::new(py);
+        for (track_name, stem) in self.inner.model_info().track_names.iter().zip(stems) {
+            let frames = stem.len() / NB_CHANNELS;
+            // Takes ownership of the samples, no copy.
+            let stem = stem.into_pyarray(py).reshape([frames, NB_CHANNELS])?;
+            dict.set_item(track_name, stem)?;
+        }
+        Ok(dict)
+    }
+
+    /// Separate an audio file into `<output_dir>/<stem>.<format>`, `format`
+    /// defaults to the input's extension. Returns the path of each stem.
+    #[pyo3(signature = (input, output_dir, format = None))]
+    fn separate_file<'py>(
+        &self,
+        py: Python<'py>,
+        input: std::path::PathBuf,
+        output_dir: std::path::PathBuf,
+        format: Option<&str>,
+    ) -> PyResult<&'py PyDict> {
+        let input = utf8_path(input)?;
+        let output_dir = utf8_path(output_dir)?;
+        let outputs = py
+            .allow_threads(|| {
+                self.inner.separate_file(
+                    &input,
+                    &output_dir,
+                    format,
+                    &EncodeOptions::default(),
+                    OverwritePolicy::Overwrite,
+                    &Control::default(),
+                )
+            })
+            .map_err(to_py_err)?;
+
+        let dict = PyDict::new(py);
+        for (track_name, path) in outputs {
+            dict.set_item(track_name, path.as_str())?;
+        }
+        Ok(dict)
+    }
+}
+
+/// Load `model` and separate one file, check `Separator.separate_file`.
+#[pyfunction]
+#[pyo3(signature = (input, output_dir, model = "2stems", models_dir = None, format = None))]
+fn separate_file<'py>(
+    py: Python<'py>,
+    input: std::path::PathBuf,
+    output_dir: std::path::PathBuf,
+    model: &str,
+    models_dir: Option<std::path::PathBuf>,
+    format: Option<&str>,
+) -> PyResult<&'py PyDict> {
+    Separator::new(py, model, models_dir)?.separate_file(py, input, output_dir, format)
+}
+
+/// Names of the models installed in `models_dir` or embedded.
+#[pyfunction]
+#[pyo3(signature = (models_dir = None))]
+fn available_models(models_dir: Option<std::path::PathBuf>) -> PyResult<Vec<&'static str>> {
+    Ok(splitter::existing_models(&self::models_dir(models_dir)?))
+}
+
+#[pymodule]
+#[pyo3(name = "rspleeter")]
+fn rspleeter_python(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Separator>()?;
+    m.add_function(wrap_pyfunction!(separate_file, m)?)?;
+    m.add_function(wrap_pyfunction!(available_models, m)?)?;
+    Ok(())
+}
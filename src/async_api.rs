@@ -0,0 +1,157 @@
+//! Async API for tokio, enabled by the `async` feature.
+//!
+//! Decoding, inference and encoding run on tokio's blocking pool, at most
+//! `max_jobs` at a time; further calls wait for a free slot without holding a
+//! blocking thread. Dropping a returned future, or the receiver of a stream,
+//! cancels its job at the next packet or segment.
+use camino::Utf8PathBuf as PathBuf;
+use std::sync::Arc;
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::{self, JoinError},
+};
+
+use crate::control::{CancellationToken, Control};
+use crate::encode::EncodeOptions;
+use crate::error::Error;
+use crate::output::OverwritePolicy;
+use crate::splitter::{Separator, SpleeterModelInfo};
+use crate::utils::AudioData;
+
+/// Segments buffered by `separate_stream` before the separation waits for
+/// the consumer.
+pub const STREAM_CAPACITY: usize = 2;
+
+/// Cancels the job when the future owning it is dropped.
+struct CancelOnDrop(CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+fn join_error(e: JoinError) -> Error {
+    match e.try_into_panic() {
+        Ok(panic) => std::panic::resume_unwind(panic),
+        // The runtime is shutting down.
+        Err(_) => Error::Cancelled,
+    }
+}
+
+/// A loaded model shared by concurrent async jobs.
+#[derive(Clone)]
+pub struct AsyncSeparator {
+    separator: Arc<Separator>,
+    jobs: Arc<Semaphore>,
+}
+
+impl AsyncSeparator {
+    /// Load the model on the blocking pool, at most `max_jobs` jobs run at
+    /// once.
+    pub async fn load(
+        model_info: &'static SpleeterModelInfo,
+        models_dir: PathBuf,
+        max_jobs: usize,
+    ) -> Result<Self, Error> {
+        let separator = task::spawn_blocking(move || Separator::load(model_info, &models_dir))
+            .await
+            .map_err(join_error)??;
+        Ok(Self::new(separator, max_jobs))
+    }
+
+    pub fn new(separator: Separator, max_jobs: usize) -> Self {
+        Self {
+            separator: Arc::new(separator),
+            jobs: Arc::new(Semaphore::new(max_jobs.max(1))),
+        }
+    }
+
+    pub fn separator(&self) -> &Separator {
+        &self.separator
+    }
+
+    /// Run `f` on the blocking pool once a job slot is free.
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Separator, &Control) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        // unwrap: the semaphore is never closed.
+        let permit = self.jobs.clone().acquire_owned().await.unwrap();
+        let control = Control::default();
+        let _cancel_on_drop = CancelOnDrop(control.cancellation_token().clone());
+        let separator = self.separator.clone();
+        task::spawn_blocking(move || {
+            let _permit = permit;
+            f(&separator, &control)
+        })
+        .await
+        .map_err(join_error)?
+    }
+
+    /// Async `Separator::separate`.
+    pub async fn separate(&self, audio_data: AudioData) -> Result<Vec<Vec<f32>>, Error> {
+        self.run(move |separator, control| separator.separate_with_control(&audio_data, control))
+            .await
+    }
+
+    /// Async `Separator::separate_file`.
+    pub async fn separate_file(
+        &self,
+        input: PathBuf,
+        out_dir: PathBuf,
+        extension: Option<String>,
+        options: EncodeOptions,
+        policy: OverwritePolicy,
+    ) -> Result<Vec<(&'static str, PathBuf)>, Error> {
+        self.run(move |separator, control| {
+            separator.separate_file(
+                &input,
+                &out_dir,
+                extension.as_deref(),
+                &options,
+                policy,
+                control,
+            )
+        })
+        .await
+    }
+
+    /// Separate segment by segment, receiving the interleaved stems of each
+    /// segment as soon as they are ready. Like `Separator::separate_segments`,
+    /// the stems are not processed.
+    ///
+    /// The separation waits while `STREAM_CAPACITY` segments are not
+    /// received yet. The last item is an error if the separation fails.
+    pub fn separate_stream(
+        &self,
+        audio_data: AudioData,
+    ) -> mpsc::Receiver<Result<Vec<Vec<f32>>, Error>> {
+        let (sender, receiver) = mpsc::channel(STREAM_CAPACITY);
+        let this = self.clone();
+        tokio::spawn(async move {
+            let result = this
+                .run({
+                    let sender = sender.clone();
+                    move |separator, control| {
+                        if sender.is_closed() {
+                            return Err(Error::Cancelled);
+                        }
+                        separator.separate_segments(&audio_data, control, |stems| {
+                            let stems = stems.into_iter().map(<[f32]>::to_vec).collect();
+                            // Fails once the receiver is dropped.
+                            sender
+                                .blocking_send(Ok(stems))
+                                .map_err(|_| Error::Cancelled)?;
+                            Ok(())
+                        })
+                    }
+                })
+                .await;
+            if let Err(e) = result {
+                let _ = sender.send(Err(e)).await;
+            }
+        });
+        receiver
+    }
+}
@@ -0,0 +1,170 @@
+//! Layered configuration.
+//!
+//! Values are resolved from, in increasing priority: built-in defaults,
+//! `$XDG_CONFIG_HOME/rspleeter/config.toml` (`~/.config/rspleeter/config.toml`
+//! when unset), `RSPLEETER_*` environment variables and command line flags.
+//!
+//! ```toml
+//! models_dir = "/opt/rspleeter/models"
+//! model = "4stems"
+//! format = "flac"
+//! segment_length = 30
+//! segment_overlap = 5
+//! jobs = 4
+//! queue_depth = 1
+//!
+//! [[processors.vocals]]
+//! type = "gate"
+//! threshold_db = -45
+//! ```
+use anyhow::{anyhow, bail, Context, Result};
+use camino::Utf8PathBuf as PathBuf;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env, fs, str::FromStr, thread};
+
+use crate::processors::ProcessorConfig;
+use crate::splitter::{SpleeterModelInfo, DEFAULT_SEGMENT_LENGTH, DEFAULT_SEGMENT_OVERLAP};
+
+/// Settings shared by the commands.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Directory holding one subdirectory per model.
+    pub models_dir: PathBuf,
+    /// Model used by `split`.
+    pub model: String,
+    /// Output container extension, the input's when unset.
+    pub format: Option<String>,
+    /// Length of the segments fed to the model, in seconds.
+    pub segment_length: usize,
+    /// Context added on both sides of a segment, in seconds.
+    pub segment_overlap: usize,
+    /// Number of stems encoded concurrently.
+    pub jobs: usize,
+    /// Number of inputs waiting between pipeline stages.
+    pub queue_depth: usize,
+    /// Post-processing chain of each stem, check `processors`.
+    pub processors: BTreeMap<String, Vec<ProcessorConfig>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            models_dir: PathBuf::from("models/models"),
+            model: "2stems".to_owned(),
+            format: None,
+            segment_length: DEFAULT_SEGMENT_LENGTH,
+            segment_overlap: DEFAULT_SEGMENT_OVERLAP,
+            jobs: thread::available_parallelism()
+                .map(|x| x.get())
+                .unwrap_or(4),
+            queue_depth: 1,
+            processors: BTreeMap::new(),
+        }
+    }
+}
+
+/// Path of the config file, whether or not it exists.
+pub fn config_path() -> Option<PathBuf> {
+    let config_home = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").ok()?).join(".config"),
+    };
+    Some(config_home.join("rspleeter").join("config.toml"))
+}
+
+/// Directory for data rspleeter can recreate, `RSPLEETER_CACHE_DIR` or
+/// `$XDG_CACHE_HOME/rspleeter` (`~/.cache/rspleeter` when unset).
+pub fn cache_dir() -> Option<PathBuf> {
+    if let Ok(dir) = env::var("RSPLEETER_CACHE_DIR") {
+        if !dir.is_empty() {
+            return Some(PathBuf::from(dir));
+        }
+    }
+    let cache_home = match env::var("XDG_CACHE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").ok()?).join(".cache"),
+    };
+    Some(cache_home.join("rspleeter"))
+}
+
+fn env_var<T: FromStr>(name: &str) -> Result<Option<T>> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow!("Invalid value of {}: {}", name, value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(anyhow!("{} is not valid UTF-8.", name)),
+    }
+}
+
+impl Config {
+    /// Defaults, overridden by the config file and then the environment.
+    ///
+    /// Values are not checked, commands `validate` the ones they use, so
+    /// that e.g. `config show` still works with an invalid config.
+    pub fn load() -> Result<Self> {
+        let mut config = match config_path() {
+            Some(path) if path.is_file() => {
+                let content = fs::read_to_string(&path)
+                    .with_context(|| anyhow!("Read config {} failed.", path))?;
+                toml::from_str(&content)
+                    .with_context(|| anyhow!("Parse config {} failed.", path))?
+            }
+            _ => Config::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Some(models_dir) = env_var("RSPLEETER_MODELS_DIR")? {
+            self.models_dir = models_dir;
+        }
+        if let Some(model) = env_var("RSPLEETER_MODEL")? {
+            self.model = model;
+        }
+        if let Some(format) = env_var::<String>("RSPLEETER_FORMAT")? {
+            self.format = Some(format).filter(|x| !x.is_empty());
+        }
+        if let Some(segment_length) = env_var("RSPLEETER_SEGMENT_LENGTH")? {
+            self.segment_length = segment_length;
+        }
+        if let Some(segment_overlap) = env_var("RSPLEETER_SEGMENT_OVERLAP")? {
+            self.segment_overlap = segment_overlap;
+        }
+        if let Some(jobs) = env_var("RSPLEETER_JOBS")? {
+            self.jobs = jobs;
+        }
+        if let Some(queue_depth) = env_var("RSPLEETER_QUEUE_DEPTH")? {
+            self.queue_depth = queue_depth;
+        }
+        Ok(())
+    }
+
+    /// Check values that are not checked by their types.
+    pub fn validate(&self) -> Result<()> {
+        if SpleeterModelInfo::get_by_name(&self.model).is_none() {
+            bail!("Unknown model: {}", self.model);
+        }
+        if self.segment_length == 0 {
+            bail!("Segment length must be positive.");
+        }
+        if self.segment_overlap >= self.segment_length {
+            bail!("Segment overlap must be shorter than the segment length.");
+        }
+        if self.jobs == 0 {
+            bail!("Jobs must be positive.");
+        }
+        for (track_name, chain) in &self.processors {
+            if !SpleeterModelInfo::all().any(|x| x.track_names.contains(&track_name.as_str())) {
+                bail!("Unknown stem in processors: {}", track_name);
+            }
+            for processor in chain {
+                processor.validate()?;
+            }
+        }
+        Ok(())
+    }
+}
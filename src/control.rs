@@ -0,0 +1,116 @@
+//! Progress reporting and cooperative cancellation.
+//!
+//! Long running operations take a [`Control`], report [`Progress`] through it
+//! and check it for cancellation between packets and segments. A cancelled
+//! operation returns [`Error::Cancelled`]; FFmpeg and TensorFlow
+//! objects are dropped on the way out and partial outputs written through
+//! `output::write_atomically` are removed.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::error::Error;
+
+/// Shared flag, cancelling any clone cancels all of them.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Progress of one stage of an operation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Progress {
+    /// Seconds of audio decoded, out of `duration` if the container knows it.
+    Decode { seconds: f64, duration: Option<f64> },
+    /// Segment `segment` of `segments` (1-based) separated.
+    Segment { segment: usize, segments: usize },
+    /// Seconds of audio encoded out of `duration`, `track` is set through
+    /// [`Control::for_track`].
+    Encode {
+        track: Option<String>,
+        seconds: f64,
+        duration: f64,
+    },
+}
+
+type ProgressCallback = Arc<dyn Fn(Progress) + Send + Sync>;
+
+/// Cancellation token and progress callback of an operation.
+///
+/// The default never cancels and reports nothing.
+#[derive(Clone, Default)]
+pub struct Control {
+    cancellation_token: CancellationToken,
+    on_progress: Option<ProgressCallback>,
+    track: Option<Arc<str>>,
+}
+
+impl Control {
+    pub fn new(cancellation_token: CancellationToken) -> Self {
+        Self {
+            cancellation_token,
+            on_progress: None,
+            track: None,
+        }
+    }
+
+    /// Call `on_progress` for every progress update, from the thread doing
+    /// the work.
+    pub fn with_progress(mut self, on_progress: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.on_progress = Some(Arc::new(on_progress));
+        self
+    }
+
+    /// Clone tagging its `Progress::Encode` reports with `track`, which tells
+    /// apart stems encoded in parallel.
+    pub fn for_track(&self, track: &str) -> Self {
+        Self {
+            track: Some(track.into()),
+            ..self.clone()
+        }
+    }
+
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation_token
+    }
+
+    pub fn report(&self, progress: Progress) {
+        if let Some(on_progress) = &self.on_progress {
+            let progress = match progress {
+                Progress::Encode {
+                    track: None,
+                    seconds,
+                    duration,
+                } => Progress::Encode {
+                    track: self.track.as_deref().map(str::to_owned),
+                    seconds,
+                    duration,
+                },
+                progress => progress,
+            };
+            on_progress(progress);
+        }
+    }
+
+    /// Returns `Err(Error::Cancelled)` once cancelled.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.cancellation_token.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
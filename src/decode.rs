@@ -3,7 +3,7 @@ use camino::Utf8Path as Path;
 use rsmpeg::{
     avcodec::{AVCodec, AVCodecContext, AVPacket},
     avformat::AVFormatContextInput,
-    avutil::AVSamples,
+    avutil::{av_q2d, AVSamples},
     error::RsmpegError,
     ffi::{self},
     swresample::SwrContext,
@@ -11,6 +11,8 @@ use rsmpeg::{
 use std::ffi::CString;
 use std::slice::from_raw_parts;
 
+use crate::control::{Control, Progress};
+use crate::error::Error;
 use crate::utils::AudioInfo;
 use crate::utils::AudioParameters;
 
@@ -106,21 +108,40 @@ fn init_decode_context(
 }
 
 /// Result<(original_audio_info, pcm_data)>
+///
+/// `dump` prints FFmpeg's description of the input to stderr. `control` is
+/// checked between packets and receives `Progress::Decode` about once per
+/// second of audio.
 pub fn decode_audio(
     audio_path: &Path,
     output_audio_info: &AudioInfo,
+    dump: bool,
+    control: &Control,
+) -> Result<(AudioParameters, Vec<u8>), Error> {
+    decode(audio_path, output_audio_info, dump, control).map_err(Error::wrap(Error::Decode))
+}
+
+fn decode(
+    audio_path: &Path,
+    output_audio_info: &AudioInfo,
+    dump: bool,
+    control: &Control,
 ) -> Result<(AudioParameters, Vec<u8>)> {
     // unwrap: &str ensures no internal null bytes.
     let audio_path = CString::new(audio_path.as_str()).unwrap();
-    let mut input_format_context =
-        AVFormatContextInput::open(&audio_path).context("Open audio file failed.")?;
+    let mut input_format_context = AVFormatContextInput::open(&audio_path)
+        .context("Open audio file failed.")
+        .map_err(|e| Error::Input(e.into()))?;
 
-    input_format_context.dump(0, &audio_path)?;
+    if dump {
+        input_format_context.dump(0, &audio_path)?;
+    }
 
     let (stream_index, decoder) = input_format_context
         .find_best_stream(ffi::AVMEDIA_TYPE_AUDIO)
         .context("Find best stream failed.")?
-        .context("Cannot find audio stream in this file.")?;
+        .context("Cannot find audio stream in this file.")
+        .map_err(|e| Error::Input(e.into()))?;
 
     let audio_parameters = {
         let stream = input_format_context.streams().get(stream_index).unwrap();
@@ -139,11 +160,24 @@ pub fn decode_audio(
 
     let mut pcm_data = Vec::new();
 
+    let duration = match input_format_context.duration {
+        ffi::AV_NOPTS_VALUE => None,
+        duration => Some(duration as f64 / ffi::AV_TIME_BASE as f64),
+    };
+    let mut reported_seconds = 0.;
     while let Some(packet) = input_format_context
         .read_packet()
         .context("Read packet failed")?
     {
+        control.check()?;
         if packet.stream_index == stream_index as i32 {
+            if packet.pts != ffi::AV_NOPTS_VALUE {
+                let seconds = packet.pts as f64 * av_q2d(audio_parameters.time_base);
+                if seconds >= reported_seconds + 1. {
+                    reported_seconds = seconds;
+                    control.report(Progress::Decode { seconds, duration });
+                }
+            }
             decode_resample_save(
                 &output_audio_info,
                 &mut decode_context,
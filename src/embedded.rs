@@ -0,0 +1,95 @@
+//! Models embedded in the binary with the `embed-models` feature.
+//!
+//! The build packs the models named by `RSPLEETER_EMBED_MODELS` (e.g.
+//! `2stems,4stems`) from `RSPLEETER_EMBED_MODELS_DIR` (`models` by default)
+//! into zip archives, deflated unless `RSPLEETER_EMBED_COMPRESSION=none`.
+//! TensorFlow only loads SavedModels from disk, so a model is unpacked into
+//! the cache dir the first time it's used, then reused by later runs as long
+//! as every file is still there.
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
+use std::{fs, io::Cursor, process};
+use tracing::{info, warn};
+use zip::ZipArchive;
+
+use crate::config;
+use crate::models;
+
+/// A model archive included in the binary.
+pub struct EmbeddedModel {
+    pub name: &'static str,
+    /// Hex digest of `archive`.
+    pub sha256: &'static str,
+    /// Zip of `<name>/...`.
+    pub archive: &'static [u8],
+}
+
+pub static EMBEDDED_MODELS: &[EmbeddedModel] =
+    include!(concat!(env!("OUT_DIR"), "/embedded_models.rs"));
+
+pub fn get(model_name: &str) -> Option<&'static EmbeddedModel> {
+    EMBEDDED_MODELS.iter().find(|x| x.name == model_name)
+}
+
+/// Names of the embedded models.
+pub fn names() -> Vec<&'static str> {
+    EMBEDDED_MODELS.iter().map(|x| x.name).collect()
+}
+
+impl EmbeddedModel {
+    /// Unpack the model if not done yet, returns a models dir holding it.
+    ///
+    /// The dir is named after the archive's digest, so a binary embedding
+    /// other models never reuses stale files.
+    pub fn unpack(&self) -> Result<PathBuf> {
+        let cache_dir = config::cache_dir().context("Cannot find the cache dir.")?;
+        let models_dir = cache_dir.join("models").join(&self.sha256[..16]);
+        if models_dir.exists() {
+            if self.is_unpacked(&models_dir)? {
+                return Ok(models_dir);
+            }
+            warn!(
+                "Embedded {} in {} is incomplete, unpacking it again.",
+                self.name, models_dir
+            );
+            fs::remove_dir_all(&models_dir)
+                .with_context(|| anyhow!("Remove {} failed.", models_dir))?;
+        }
+
+        // Unpack next to the destination and rename, so that concurrent runs
+        // never see a partial model.
+        let partial_dir =
+            cache_dir
+                .join("models")
+                .join(format!(".{}.{}", &self.sha256[..16], process::id()));
+        let _ = fs::remove_dir_all(&partial_dir);
+        models::unpack(Cursor::new(self.archive), &partial_dir)
+            .with_context(|| anyhow!("Unpack embedded {} failed.", self.name))?;
+        if let Err(e) = fs::rename(&partial_dir, &models_dir) {
+            let _ = fs::remove_dir_all(&partial_dir);
+            // Another run got there first.
+            if !self.is_unpacked(&models_dir)? {
+                return Err(e).with_context(|| anyhow!("Create {} failed.", models_dir));
+            }
+        }
+        info!("Unpacked embedded {} to {}", self.name, models_dir);
+        Ok(models_dir)
+    }
+
+    /// Whether every file of the archive is in `models_dir` with its size.
+    fn is_unpacked(&self, models_dir: &Path) -> Result<bool> {
+        let mut archive =
+            ZipArchive::new(Cursor::new(self.archive)).context("Read model archive failed.")?;
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i).context("Read archive entry failed.")?;
+            if entry.is_dir() {
+                continue;
+            }
+            match fs::metadata(models_dir.join(entry.name())) {
+                Ok(metadata) if metadata.is_file() && metadata.len() == entry.size() => {}
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+}
@@ -1,23 +1,98 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use camino::Utf8Path as Path;
+use cstr::cstr;
 use rsmpeg::{
-    avcodec::{AVCodec, AVCodecContext},
-    avformat::{AVFormatContextOutput, AVOutputFormat},
-    avutil::{AVFrame, AVRational},
+    avcodec::{AVCodec, AVCodecContext, AVCodecParameters},
+    avformat::{
+        AVFormatContextOutput, AVIOContextContainer, AVIOContextCustom, AVOutputFormat,
+        SeekCallback, WritePacketCallback,
+    },
+    avutil::{AVAudioFifo, AVChannelLayout, AVDictionary, AVFrame, AVMem, AVRational},
     error::RsmpegError,
     ffi::{self},
     swresample::SwrContext,
     UnsafeDerefMut,
 };
-use std::{ffi::CString, slice};
+use serde::Serialize;
+use std::{
+    ffi::{c_void, CStr, CString},
+    io::{self, Cursor, Seek, SeekFrom, Write},
+    mem, ptr, slice,
+    sync::{Arc, Mutex},
+};
 
-use crate::utils::AudioInfo;
+use crate::control::{Control, Progress};
+use crate::error::Error;
+use crate::ni_stems;
 use crate::utils::AudioParameters;
+use crate::utils::{self, AudioInfo};
+
+/// Dither applied when the output samples are quantized to integers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Dither {
+    /// Plain truncation.
+    None,
+    /// Triangular probability density function dither.
+    #[default]
+    Tpdf,
+    /// TPDF dither with high-pass filtered noise.
+    TpdfHp,
+    /// TPDF dither with Lipshitz noise shaping.
+    Lipshitz,
+    /// TPDF dither with Shibata noise shaping.
+    Shibata,
+    /// TPDF dither with low Shibata noise shaping.
+    LowShibata,
+    /// TPDF dither with high Shibata noise shaping.
+    HighShibata,
+    /// TPDF dither with F-weighted noise shaping.
+    FWeighted,
+    /// TPDF dither with E-weighted noise shaping.
+    EWeighted,
+    /// TPDF dither with modified E-weighted noise shaping.
+    ModifiedEWeighted,
+}
+
+impl Dither {
+    fn method(self) -> ffi::SwrDitherType {
+        match self {
+            Dither::None => ffi::SWR_DITHER_NONE,
+            Dither::Tpdf => ffi::SWR_DITHER_TRIANGULAR,
+            Dither::TpdfHp => ffi::SWR_DITHER_TRIANGULAR_HIGHPASS,
+            Dither::Lipshitz => ffi::SWR_DITHER_NS_LIPSHITZ,
+            Dither::Shibata => ffi::SWR_DITHER_NS_SHIBATA,
+            Dither::LowShibata => ffi::SWR_DITHER_NS_LOW_SHIBATA,
+            Dither::HighShibata => ffi::SWR_DITHER_NS_HIGH_SHIBATA,
+            Dither::FWeighted => ffi::SWR_DITHER_NS_F_WEIGHTED,
+            Dither::EWeighted => ffi::SWR_DITHER_NS_IMPROVED_E_WEIGHTED,
+            Dither::ModifiedEWeighted => ffi::SWR_DITHER_NS_MODIFIED_E_WEIGHTED,
+        }
+    }
+}
+
+/// Overrides of the output audio format, `None` keeps the original value.
+#[derive(Clone, Default)]
+pub struct EncodeOptions {
+    /// Output codec, by default the original one or the container's default.
+    pub codec_id: Option<ffi::AVCodecID>,
+    pub sample_rate: Option<usize>,
+    pub ch_layout: Option<AVChannelLayout>,
+    pub sample_fmt: Option<ffi::AVSampleFormat>,
+    /// Bits per sample actually used, e.g. 24 for 24-bit FLAC stored in s32.
+    pub bits_per_sample: Option<i32>,
+    pub dither: Dither,
+}
+
+fn is_integer_sample_fmt(sample_fmt: ffi::AVSampleFormat) -> bool {
+    let packed = unsafe { ffi::av_get_packed_sample_fmt(sample_fmt) };
+    packed != ffi::AV_SAMPLE_FMT_FLT && packed != ffi::AV_SAMPLE_FMT_DBL
+}
 
 /// Change pcm samples to original format.
 fn init_resample_context(
     audio_parameters: &AudioParameters,
     pcm_data_info: &AudioInfo,
+    options: &EncodeOptions,
 ) -> Result<SwrContext> {
     let mut resample_context = SwrContext::new(
         &audio_parameters.codecpar.ch_layout(),
@@ -28,12 +103,231 @@ fn init_resample_context(
         pcm_data_info.sample_rate as i32,
     )
     .context("SwrContext parameters incorrect.")?;
+
+    // Float output needs no dither, and swresample only dithers when
+    // quantizing, so this is a no-op for float formats anyway.
+    let dither = if is_integer_sample_fmt(audio_parameters.codecpar.format) {
+        options.dither
+    } else {
+        Dither::None
+    };
+    let bits_per_sample = audio_parameters.codecpar.bits_per_raw_sample;
+    unsafe {
+        let ctx = resample_context.as_mut_ptr() as *mut c_void;
+        if ffi::av_opt_set_int(
+            ctx,
+            cstr!("dither_method").as_ptr(),
+            dither.method() as i64,
+            0,
+        ) < 0
+        {
+            bail!("Set dither method failed.");
+        }
+        // Dither down to the real bit depth instead of the container sample
+        // size, e.g. 24 bits inside s32.
+        if bits_per_sample > 0
+            && ffi::av_opt_set_int(
+                ctx,
+                cstr!("output_sample_bits").as_ptr(),
+                bits_per_sample as i64,
+                0,
+            ) < 0
+        {
+            bail!("Set output sample bits failed.");
+        }
+    }
+
     resample_context
         .init()
         .context("Init resample context failed.")?;
     Ok(resample_context)
 }
 
+/// Sample formats supported by the encoder, `None` means unknown.
+fn supported_sample_fmts(encoder: &AVCodec) -> Option<&[ffi::AVSampleFormat]> {
+    let mut configs: *const c_void = ptr::null();
+    let mut num_configs = 0;
+    let ret = unsafe {
+        ffi::avcodec_get_supported_config(
+            ptr::null(),
+            encoder.as_ptr(),
+            ffi::AV_CODEC_CONFIG_SAMPLE_FORMAT,
+            0,
+            &mut configs,
+            &mut num_configs,
+        )
+    };
+    if ret < 0 || configs.is_null() {
+        return None;
+    }
+    Some(unsafe {
+        slice::from_raw_parts(configs as *const ffi::AVSampleFormat, num_configs as usize)
+    })
+}
+
+/// Pick the requested sample format, or its planar/packed twin when the
+/// encoder only supports that.
+fn choose_sample_fmt(
+    encoder: &AVCodec,
+    sample_fmt: ffi::AVSampleFormat,
+) -> Result<ffi::AVSampleFormat> {
+    let supported = match supported_sample_fmts(encoder) {
+        Some(supported) => supported,
+        None => return Ok(sample_fmt),
+    };
+    let packed = unsafe { ffi::av_get_packed_sample_fmt(sample_fmt) };
+    supported
+        .iter()
+        .copied()
+        .find(|&x| x == sample_fmt)
+        .or_else(|| {
+            supported
+                .iter()
+                .copied()
+                .find(|&x| unsafe { ffi::av_get_packed_sample_fmt(x) } == packed)
+        })
+        .with_context(|| anyhow!("Sample format({}) not supported by encoder.", sample_fmt))
+}
+
+/// Sample format used when only the bit depth is given.
+fn sample_fmt_for_bits(bits_per_sample: i32) -> Result<ffi::AVSampleFormat> {
+    Ok(match bits_per_sample {
+        1..=8 => ffi::AV_SAMPLE_FMT_U8,
+        9..=16 => ffi::AV_SAMPLE_FMT_S16,
+        17..=32 => ffi::AV_SAMPLE_FMT_S32,
+        _ => bail!("Unsupported bit depth: {}", bits_per_sample),
+    })
+}
+
+/// Apply output overrides on top of the original audio parameters.
+///
+/// Unless a codec is requested, the original codec is kept when the output
+/// container can hold it, otherwise the container's default audio codec is
+/// used.
+fn resolve_audio_parameters(
+    output_format: &AVOutputFormat,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    allow_original_codec: bool,
+) -> Result<(AVCodec, AudioParameters)> {
+    let original_codec_id = audio_parameters.codecpar.codec_id;
+    let keep_codec = match options.codec_id {
+        Some(codec_id) => codec_id == original_codec_id,
+        None => {
+            allow_original_codec
+                && unsafe {
+                    ffi::avformat_query_codec(
+                        output_format.as_ptr(),
+                        original_codec_id,
+                        ffi::FF_COMPLIANCE_NORMAL as i32,
+                    )
+                } == 1
+        }
+    };
+
+    let mut codecpar = if keep_codec {
+        audio_parameters.codecpar.clone()
+    } else {
+        let mut codecpar = AVCodecParameters::new();
+        let original = &audio_parameters.codecpar;
+        let codecpar_mut = unsafe { codecpar.deref_mut() };
+        codecpar_mut.codec_type = ffi::AVMEDIA_TYPE_AUDIO;
+        codecpar_mut.codec_id = options.codec_id.unwrap_or(output_format.audio_codec);
+        codecpar_mut.sample_rate = original.sample_rate;
+        codecpar_mut.format = original.format;
+        if unsafe { ffi::av_channel_layout_copy(&mut codecpar_mut.ch_layout, &original.ch_layout) }
+            < 0
+        {
+            bail!("Copy channel layout failed.");
+        }
+        codecpar
+    };
+
+    let encoder = AVCodec::find_encoder(codecpar.codec_id)
+        .with_context(|| anyhow!("encoder({}) not found.", codecpar.codec_id))?;
+
+    let codecpar_mut = unsafe { codecpar.deref_mut() };
+    if let Some(sample_rate) = options.sample_rate {
+        codecpar_mut.sample_rate = sample_rate as i32;
+    }
+    if let Some(ch_layout) = &options.ch_layout {
+        if unsafe { ffi::av_channel_layout_copy(&mut codecpar_mut.ch_layout, ch_layout.as_ptr()) }
+            < 0
+        {
+            bail!("Copy channel layout failed.");
+        }
+    }
+    match (options.sample_fmt, options.bits_per_sample) {
+        (Some(sample_fmt), _) => {
+            codecpar_mut.format = choose_sample_fmt(&encoder, sample_fmt)?;
+        }
+        (None, Some(bits_per_sample)) => {
+            codecpar_mut.format =
+                choose_sample_fmt(&encoder, sample_fmt_for_bits(bits_per_sample)?)?;
+        }
+        // The new encoder may not take the original format, fall back to its
+        // preferred one.
+        (None, None) if !keep_codec => {
+            let original_fmt = codecpar_mut.format;
+            codecpar_mut.format = choose_sample_fmt(&encoder, original_fmt)
+                .ok()
+                .or_else(|| supported_sample_fmts(&encoder)?.first().copied())
+                .unwrap_or(original_fmt);
+        }
+        (None, None) => {}
+    }
+    if let Some(bits_per_sample) = options.bits_per_sample {
+        codecpar_mut.bits_per_raw_sample = bits_per_sample;
+    } else if codecpar_mut.format != audio_parameters.codecpar.format {
+        // The original depth describes the original format, let the encoder
+        // derive it from the new one.
+        codecpar_mut.bits_per_raw_sample = 0;
+    }
+
+    Ok((
+        encoder,
+        AudioParameters {
+            time_base: audio_parameters.time_base,
+            codecpar,
+        },
+    ))
+}
+
+/// Output audio format a split would produce.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputDescription {
+    pub container: String,
+    pub codec: String,
+    pub sample_rate: i32,
+    pub channel_layout: String,
+    pub sample_format: String,
+    pub bits_per_sample: Option<i32>,
+}
+
+/// Describe the output written for `extension` without encoding anything.
+pub fn describe_output(
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    extension: &str,
+) -> Result<OutputDescription> {
+    let filename = CString::new(format!("output.{}", extension)).unwrap();
+    let output_format = AVOutputFormat::guess_format(None, Some(&filename), None)
+        .with_context(|| anyhow!("Unknown output format: {}", extension))?;
+    let (_, audio_parameters) =
+        resolve_audio_parameters(&output_format, audio_parameters, options, true)?;
+    let codecpar = &audio_parameters.codecpar;
+    Ok(OutputDescription {
+        container: unsafe { CStr::from_ptr(output_format.name) }
+            .to_string_lossy()
+            .into_owned(),
+        codec: utils::codec_name(codecpar.codec_id),
+        sample_rate: codecpar.sample_rate,
+        channel_layout: utils::describe_ch_layout(&codecpar.ch_layout),
+        sample_format: utils::sample_fmt_name(codecpar.format),
+        bits_per_sample: (codecpar.bits_per_raw_sample > 0).then_some(codecpar.bits_per_raw_sample),
+    })
+}
+
 fn init_encode_context(
     encoder: &AVCodec,
     audio_parameters: &AudioParameters,
@@ -58,6 +352,7 @@ fn init_encode_context(
 fn write_frame(
     output_format_context: &mut AVFormatContextOutput,
     encode_context: &mut AVCodecContext,
+    stream_index: usize,
     frame: Option<&AVFrame>,
 ) -> Result<()> {
     encode_context
@@ -72,12 +367,17 @@ fn write_frame(
             }
             Err(e) => return Err(e).context("receive packet failed."),
         };
+        packet.set_stream_index(stream_index as i32);
         packet.rescale_ts(
             encode_context.time_base,
-            output_format_context.streams().get(0).unwrap().time_base,
+            output_format_context
+                .streams()
+                .get(stream_index)
+                .unwrap()
+                .time_base,
         );
         output_format_context
-            .write_frame(&mut packet)
+            .interleaved_write_frame(&mut packet)
             .context("Write frame failed.")?;
     }
     Ok(())
@@ -108,109 +408,749 @@ fn create_output_frame(audio_parameters: &AudioParameters) -> AVFrame {
     output_frame
 }
 
-pub fn encode_pcm_data(
-    pcm_data: &[u8],
-    pcm_audio_info: &AudioInfo,
-    audio_parameters: &AudioParameters,
-    output_path: &Path,
-) -> Result<()> {
+pub(crate) fn create_output_format_context(output_path: &Path) -> Result<AVFormatContextOutput> {
     let output_path = CString::new(output_path.as_str()).unwrap();
 
-    let encoder = AVCodec::find_encoder(audio_parameters.codecpar.codec_id)
-        .with_context(|| anyhow!("encoder({}) not found.", audio_parameters.codecpar.codec_id))?;
-    let mut encode_context =
-        init_encode_context(&encoder, &audio_parameters).context("Init encode context failed.")?;
-
     let mut output_format_context = AVFormatContextOutput::create(&output_path)
         .context("Create output format context failed.")?;
 
     if let Some(output_format) = AVOutputFormat::guess_format(None, Some(&output_path), None) {
         output_format_context.set_oformat(output_format);
     }
+    Ok(output_format_context)
+}
 
-    // Some container formats (like MP4) require global headers to be present.
-    // Mark the encoder so that it behaves accordingly.
-    if output_format_context.oformat().flags & ffi::AVFMT_GLOBALHEADER as i32 != 0 {
-        encode_context.set_flags(encode_context.flags | ffi::AV_CODEC_FLAG_GLOBAL_HEADER as i32);
-    }
-
-    {
-        let mut new_audio_stream = output_format_context.new_stream();
-        // Use extracted codecpar from encode_context since it contains
-        // extradata(adts header when encoding aac), while codecpar from
-        // AVStream of input_format_context doesn't.
-        new_audio_stream.set_codecpar(encode_context.extract_codecpar());
-        new_audio_stream.set_time_base(audio_parameters.time_base);
-    }
-    output_format_context
-        .write_header(&mut None)
-        .context("Write header failed.")?;
+/// Encoder state of one audio stream in the output file.
+pub(crate) struct StreamEncoder {
+    stream_index: usize,
+    audio_parameters: AudioParameters,
+    encode_context: AVCodecContext,
+    resample_context: SwrContext,
+    /// Resampled samples waiting for a full encoder frame.
+    fifo: AVAudioFifo,
+    pts: i64,
+}
 
-    let resample_context = init_resample_context(audio_parameters, pcm_audio_info)
-        .context("Init encode resample context failed.")?;
+impl StreamEncoder {
+    /// Add a new audio stream to the output, must be called before the
+    /// header is written.
+    pub(crate) fn new(
+        output_format_context: &mut AVFormatContextOutput,
+        pcm_audio_info: &AudioInfo,
+        audio_parameters: &AudioParameters,
+        options: &EncodeOptions,
+        allow_original_codec: bool,
+        title: Option<&str>,
+        disposition: i32,
+    ) -> Result<Self> {
+        let (encoder, audio_parameters) = resolve_audio_parameters(
+            &output_format_context.oformat(),
+            audio_parameters,
+            options,
+            allow_original_codec,
+        )
+        .context("Resolve output audio parameters failed.")?;
+        let mut encode_context = init_encode_context(&encoder, &audio_parameters)
+            .context("Init encode context failed.")?;
 
-    let samples_per_batch = encode_context.frame_size as usize;
-    let sample_size = pcm_audio_info.sample_size * pcm_audio_info.ch_layout.nb_channels as usize;
-    let num_samples = pcm_data.len() / sample_size;
-    let num_batches = (num_samples + samples_per_batch - 1) / samples_per_batch;
-    let size_per_batch = samples_per_batch * sample_size;
+        // Some container formats (like MP4) require global headers to be present.
+        // Mark the encoder so that it behaves accordingly.
+        if output_format_context.oformat().flags & ffi::AVFMT_GLOBALHEADER as i32 != 0 {
+            encode_context
+                .set_flags(encode_context.flags | ffi::AV_CODEC_FLAG_GLOBAL_HEADER as i32);
+        }
 
-    let mut sample_offset = 0;
-    let mut pts = 0;
+        let stream_index = {
+            let mut new_audio_stream = output_format_context.new_stream();
+            // Use extracted codecpar from encode_context since it contains
+            // extradata(adts header when encoding aac), while codecpar from
+            // AVStream of input_format_context doesn't.
+            new_audio_stream.set_codecpar(encode_context.extract_codecpar());
+            new_audio_stream.set_time_base(audio_parameters.time_base);
+            unsafe { new_audio_stream.deref_mut() }.disposition = disposition;
+            if let Some(title) = title {
+                let title = CString::new(title).unwrap();
+                // `handler_name` is what MP4 shows as the track name.
+                let metadata = AVDictionary::new(cstr!("title"), &title, 0).set(
+                    cstr!("handler_name"),
+                    &title,
+                    0,
+                );
+                new_audio_stream.set_metadata(Some(metadata));
+            }
+            new_audio_stream.index as usize
+        };
 
-    for i in 0..num_batches {
-        let process_samples = samples_per_batch.min(num_samples - sample_offset);
-        let begin = i * size_per_batch;
-        let len = process_samples * sample_size;
+        let resample_context = init_resample_context(&audio_parameters, pcm_audio_info, options)
+            .context("Init encode resample context failed.")?;
 
-        let input_frame = create_input_frame(
-            process_samples,
-            pcm_audio_info,
-            &pcm_data[begin..begin + len],
+        let fifo = AVAudioFifo::new(
+            audio_parameters.codecpar.format,
+            audio_parameters.codecpar.ch_layout.nb_channels,
+            1,
         );
-        let mut output_frame = create_output_frame(audio_parameters);
 
-        resample_context
-            .convert_frame(Some(&input_frame), &mut output_frame)
-            .context("Convert pcm frame to output frame failed.")?;
+        Ok(Self {
+            stream_index,
+            audio_parameters,
+            encode_context,
+            resample_context,
+            fifo,
+            pts: 0,
+        })
+    }
 
-        output_frame.set_pts(pts);
+    /// Encoders with variable frame size (e.g. PCM) report zero.
+    fn frame_size(&self) -> usize {
+        match self.encode_context.frame_size {
+            0 => 1024,
+            frame_size => frame_size as usize,
+        }
+    }
 
+    /// Push resampled samples into fifo, `None` flushes the resampler.
+    fn resample(&mut self, input_frame: Option<&AVFrame>) -> Result<()> {
+        let mut output_frame = create_output_frame(&self.audio_parameters);
+        self.resample_context
+            .convert_frame(input_frame, &mut output_frame)
+            .context("Convert pcm frame to output frame failed.")?;
         if output_frame.nb_samples > 0 {
+            unsafe {
+                self.fifo
+                    .write(output_frame.data.as_ptr() as _, output_frame.nb_samples)
+            }
+            .context("Write audio fifo failed.")?;
+        }
+        Ok(())
+    }
+
+    /// Encode frames from the fifo while at least `min_samples` are buffered.
+    fn drain_fifo(
+        &mut self,
+        output_format_context: &mut AVFormatContextOutput,
+        min_samples: usize,
+    ) -> Result<()> {
+        let frame_size = self.frame_size();
+        while self.fifo.size() as usize >= min_samples.max(1) {
+            let nb_samples = frame_size.min(self.fifo.size() as usize);
+            let mut output_frame = create_output_frame(&self.audio_parameters);
+            output_frame.set_nb_samples(nb_samples as i32);
+            output_frame
+                .alloc_buffer()
+                .context("Alloc output frame failed.")?;
+            unsafe {
+                self.fifo.read(
+                    output_frame.deref_mut().data.as_mut_ptr() as _,
+                    nb_samples as i32,
+                )
+            }
+            .context("Read audio fifo failed.")?;
+            output_frame.set_pts(self.pts);
+            self.pts += nb_samples as i64;
+
             write_frame(
-                &mut output_format_context,
-                &mut encode_context,
+                output_format_context,
+                &mut self.encode_context,
+                self.stream_index,
                 Some(&output_frame),
             )
             .context("Write frame failed.")?;
         }
+        Ok(())
+    }
 
-        pts += output_frame.nb_samples as i64;
-        sample_offset += process_samples;
+    /// Start timestamps at `start_time` instead of zero.
+    pub(crate) fn set_start_time(&mut self, start_time: i64, time_base: AVRational) {
+        self.pts =
+            unsafe { ffi::av_rescale_q(start_time, time_base, self.encode_context.time_base) };
     }
 
-    // Flushing resample context
-    {
-        let mut output_frame = create_output_frame(audio_parameters);
+    /// Encode interleaved pcm samples, can be called repeatedly.
+    pub(crate) fn encode(
+        &mut self,
+        output_format_context: &mut AVFormatContextOutput,
+        pcm_audio_info: &AudioInfo,
+        pcm_data: &[u8],
+    ) -> Result<()> {
+        let samples_per_batch = self.frame_size();
+        let sample_size =
+            pcm_audio_info.sample_size * pcm_audio_info.ch_layout.nb_channels as usize;
+        let num_samples = pcm_data.len() / sample_size;
+        let num_batches = (num_samples + samples_per_batch - 1) / samples_per_batch;
+        let size_per_batch = samples_per_batch * sample_size;
+
+        let mut sample_offset = 0;
 
-        resample_context
-            .convert_frame(None, &mut output_frame)
+        for i in 0..num_batches {
+            let process_samples = samples_per_batch.min(num_samples - sample_offset);
+            let begin = i * size_per_batch;
+            let len = process_samples * sample_size;
+
+            let input_frame = create_input_frame(
+                process_samples,
+                pcm_audio_info,
+                &pcm_data[begin..begin + len],
+            );
+            self.resample(Some(&input_frame))?;
+            self.drain_fifo(output_format_context, samples_per_batch)?;
+
+            sample_offset += process_samples;
+        }
+        Ok(())
+    }
+
+    /// Flush resampler, fifo and encoder.
+    pub(crate) fn finish(
+        &mut self,
+        output_format_context: &mut AVFormatContextOutput,
+    ) -> Result<()> {
+        self.resample(None)
             .context("Flushing resample context failed.")?;
+        self.drain_fifo(output_format_context, 0)?;
+        write_frame(
+            output_format_context,
+            &mut self.encode_context,
+            self.stream_index,
+            None,
+        )
+        .context("Flush encode_context failed.")
+    }
+}
 
-        output_frame.set_pts(pts);
+/// Shared handle of the writer behind a custom AVIO context.
+type SharedWriter<W> = Arc<Mutex<W>>;
 
-        if output_frame.nb_samples > 0 {
-            write_frame(
+/// Muxer options making seek-dependent containers streamable.
+fn streamable_muxer_options(output_format_context: &AVFormatContextOutput) -> Option<AVDictionary> {
+    let name = unsafe { CStr::from_ptr(output_format_context.oformat().name) };
+    match name.to_bytes() {
+        // Without seeking, moov cannot be written after mdat, use fragments.
+        b"mp4" | b"mov" | b"ipod" | b"ismv" => Some(AVDictionary::new(
+            cstr!("movflags"),
+            cstr!("frag_keyframe+empty_moov+default_base_moof"),
+            0,
+        )),
+        b"matroska" => Some(AVDictionary::new(cstr!("live"), cstr!("1"), 0)),
+        _ => None,
+    }
+}
+
+/// Create an output format context for `format` (a file extension such as
+/// `flac` or `mp4`) writing into `writer`.
+fn create_custom_output_format_context<W: Write + Send + 'static>(
+    format: &str,
+    writer: SharedWriter<W>,
+    seek: Option<SeekCallback>,
+) -> Result<AVFormatContextOutput> {
+    let filename = CString::new(format!("output.{}", format)).unwrap();
+    let output_format = AVOutputFormat::guess_format(None, Some(&filename), None)
+        .with_context(|| anyhow!("Unknown output format: {}", format))?;
+
+    let write_packet: WritePacketCallback =
+        Box::new(
+            move |_, buf: &[u8]| match writer.lock().unwrap().write_all(buf) {
+                Ok(()) => buf.len() as i32,
+                Err(_) => ffi::AVERROR_EXTERNAL,
+            },
+        );
+    let io_context = AVIOContextCustom::alloc_context(
+        AVMem::new(4096),
+        true,
+        vec![],
+        None,
+        Some(write_packet),
+        seek,
+    );
+
+    AVFormatContextOutput::builder()
+        .filename(&filename)
+        .format(&output_format)
+        .io_context(AVIOContextContainer::Custom(io_context))
+        .build()
+        .context("Create output format context failed.")
+}
+
+fn seek_callback<W: Seek + Send + 'static>(writer: SharedWriter<W>) -> SeekCallback {
+    Box::new(move |_, offset, whence| {
+        let mut writer = writer.lock().unwrap();
+        if whence & ffi::AVSEEK_SIZE as i32 != 0 {
+            let size = (|| {
+                let position = writer.stream_position()?;
+                let size = writer.seek(SeekFrom::End(0))?;
+                writer.seek(SeekFrom::Start(position))?;
+                io::Result::Ok(size as i64)
+            })();
+            return size.unwrap_or(-1);
+        }
+        // SEEK_SET, SEEK_CUR and SEEK_END.
+        let position = match whence & !(ffi::AVSEEK_FORCE as i32) {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return -1,
+        };
+        writer.seek(position).map(|x| x as i64).unwrap_or(-1)
+    })
+}
+
+/// Take the writer back once the format context owning the callbacks is
+/// dropped.
+fn into_inner<W>(writer: SharedWriter<W>) -> W {
+    Arc::try_unwrap(writer)
+        .ok()
+        .expect("writer still shared")
+        .into_inner()
+        .unwrap()
+}
+
+/// Encode into a seekable writer in the given `format`, returns the writer.
+pub fn encode_pcm_data_to_writer<W: Write + Seek + Send + 'static>(
+    pcm_data: &[u8],
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+    format: &str,
+    writer: W,
+) -> Result<W, Error> {
+    let writer = Arc::new(Mutex::new(writer));
+    create_custom_output_format_context(
+        format,
+        writer.clone(),
+        Some(seek_callback(writer.clone())),
+    )
+    .and_then(|output_format_context| {
+        encode_pcm_data_with_context(
+            output_format_context,
+            pcm_data,
+            pcm_audio_info,
+            audio_parameters,
+            options,
+            control,
+            None,
+        )
+    })
+    .map_err(Error::wrap(Error::Encode))?;
+    Ok(into_inner(writer))
+}
+
+/// Encode into a non-seekable writer (e.g. a socket or pipe).
+///
+/// Containers needing seek are written in their streamable variants, e.g.
+/// fragmented MP4.
+pub fn encode_pcm_data_to_stream<W: Write + Send + 'static>(
+    pcm_data: &[u8],
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+    format: &str,
+    writer: W,
+) -> Result<W, Error> {
+    let writer = Arc::new(Mutex::new(writer));
+    create_custom_output_format_context(format, writer.clone(), None)
+        .and_then(|output_format_context| {
+            let muxer_options = streamable_muxer_options(&output_format_context);
+            encode_pcm_data_with_context(
+                output_format_context,
+                pcm_data,
+                pcm_audio_info,
+                audio_parameters,
+                options,
+                control,
+                muxer_options,
+            )
+        })
+        .map_err(Error::wrap(Error::Encode))?;
+    Ok(into_inner(writer))
+}
+
+/// Encode into memory in the given `format`.
+pub fn encode_pcm_data_to_vec(
+    pcm_data: &[u8],
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+    format: &str,
+) -> Result<Vec<u8>, Error> {
+    encode_pcm_data_to_writer(
+        pcm_data,
+        pcm_audio_info,
+        audio_parameters,
+        options,
+        control,
+        format,
+        Cursor::new(Vec::new()),
+    )
+    .map(Cursor::into_inner)
+}
+
+pub fn encode_pcm_data(
+    pcm_data: &[u8],
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+    output_path: &Path,
+) -> Result<(), Error> {
+    create_output_format_context(output_path)
+        .and_then(|output_format_context| {
+            encode_pcm_data_with_context(
+                output_format_context,
+                pcm_data,
+                pcm_audio_info,
+                audio_parameters,
+                options,
+                control,
+                None,
+            )
+        })
+        .map_err(Error::wrap(Error::Encode))
+}
+
+fn encode_pcm_data_with_context(
+    mut output_format_context: AVFormatContextOutput,
+    pcm_data: &[u8],
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+    mut muxer_options: Option<AVDictionary>,
+) -> Result<()> {
+    let mut stream_encoder = StreamEncoder::new(
+        &mut output_format_context,
+        pcm_audio_info,
+        audio_parameters,
+        options,
+        true,
+        None,
+        0,
+    )?;
+
+    output_format_context
+        .write_header(&mut muxer_options)
+        .context("Write header failed.")?;
+
+    encode_streams(
+        &mut output_format_context,
+        &mut [stream_encoder],
+        pcm_audio_info,
+        &[pcm_data],
+        control,
+    )?;
+
+    output_format_context
+        .write_trailer()
+        .context("Write trailer failed.")?;
+
+    Ok(())
+}
+
+/// Encode one pcm buffer per stream and flush every stream.
+fn encode_streams(
+    output_format_context: &mut AVFormatContextOutput,
+    stream_encoders: &mut [StreamEncoder],
+    pcm_audio_info: &AudioInfo,
+    pcm_datas: &[&[u8]],
+    control: &Control,
+) -> Result<()> {
+    // Feed the streams one second at a time so that the muxer can interleave
+    // packets without buffering whole tracks.
+    let sample_size = pcm_audio_info.sample_size * pcm_audio_info.ch_layout.nb_channels as usize;
+    let chunk_size = pcm_audio_info.sample_rate * sample_size;
+    let num_chunks = pcm_datas
+        .iter()
+        .map(|pcm_data| (pcm_data.len() + chunk_size - 1) / chunk_size)
+        .max()
+        .unwrap_or(0);
+    let duration = pcm_datas.iter().map(|x| x.len()).max().unwrap_or(0) as f64
+        / (pcm_audio_info.sample_rate * sample_size) as f64;
+    for i in 0..num_chunks {
+        control.check()?;
+        for (stream_encoder, pcm_data) in stream_encoders.iter_mut().zip(pcm_datas) {
+            let begin = (i * chunk_size).min(pcm_data.len());
+            let end = (begin + chunk_size).min(pcm_data.len());
+            stream_encoder.encode(output_format_context, pcm_audio_info, &pcm_data[begin..end])?;
+        }
+        control.report(Progress::Encode {
+            track: None,
+            seconds: ((i + 1) as f64).min(duration),
+            duration,
+        });
+    }
+    for stream_encoder in stream_encoders {
+        stream_encoder.finish(output_format_context)?;
+    }
+    Ok(())
+}
+
+/// Write a Native Instruments stem file: the mix followed by four stems,
+/// with `metadata` stored in the `stem` atom.
+pub fn encode_ni_stems(
+    mix: &[u8],
+    stems: &[(&str, &[u8])],
+    metadata: &str,
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+    output_path: &Path,
+) -> Result<(), Error> {
+    write_ni_stems(
+        mix,
+        stems,
+        metadata,
+        pcm_audio_info,
+        audio_parameters,
+        options,
+        control,
+        output_path,
+    )
+    .map_err(Error::wrap(Error::Encode))
+}
+
+fn write_ni_stems(
+    mix: &[u8],
+    stems: &[(&str, &[u8])],
+    metadata: &str,
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+    output_path: &Path,
+) -> Result<()> {
+    if stems.len() != 4 {
+        bail!("NI stem file needs exactly 4 stems, got {}.", stems.len());
+    }
+    let mut output_format_context = create_output_format_context(output_path)?;
+
+    // Players use the enabled (default) track as the mix, stems are disabled.
+    let streams = [("Mix", mix, ffi::AV_DISPOSITION_DEFAULT as i32)]
+        .into_iter()
+        .chain(stems.iter().map(|&(name, pcm_data)| (name, pcm_data, 0)));
+    let mut stream_encoders = Vec::new();
+    let mut pcm_datas = Vec::new();
+    for (name, pcm_data, disposition) in streams {
+        let stream_encoder = StreamEncoder::new(
+            &mut output_format_context,
+            pcm_audio_info,
+            audio_parameters,
+            options,
+            true,
+            Some(name),
+            disposition,
+        )
+        .with_context(|| anyhow!("Create stream for {} failed.", name))?;
+        stream_encoders.push(stream_encoder);
+        pcm_datas.push(pcm_data);
+    }
+
+    output_format_context
+        .write_header(&mut None)
+        .context("Write header failed.")?;
+
+    encode_streams(
+        &mut output_format_context,
+        &mut stream_encoders,
+        pcm_audio_info,
+        &pcm_datas,
+        control,
+    )?;
+
+    output_format_context
+        .write_trailer()
+        .context("Write trailer failed.")?;
+    drop(output_format_context);
+
+    ni_stems::insert_stem_atom(output_path, metadata).context("Insert stem atom failed.")?;
+
+    Ok(())
+}
+
+/// Whether the container stores all stems interleaved in a single
+/// multichannel stream instead of one stream per stem.
+fn is_interleaved_container(output_format_context: &AVFormatContextOutput) -> bool {
+    let name = unsafe { CStr::from_ptr(output_format_context.oformat().name) };
+    matches!(name.to_bytes(), b"wav" | b"w64")
+}
+
+/// Write every stem into one file.
+///
+/// Containers supporting multiple audio streams (e.g. MKA, MP4) get one
+/// stream per stem titled with the track name. WAV and W64 get a single PCM
+/// stream with the stems' channels concatenated in track order, the mapping
+/// is recorded in the `comment` tag.
+pub fn encode_multitrack_pcm_data(
+    stems: &[(&str, &[u8])],
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+    output_path: &Path,
+) -> Result<(), Error> {
+    write_multitrack_pcm_data(
+        stems,
+        pcm_audio_info,
+        audio_parameters,
+        options,
+        control,
+        output_path,
+    )
+    .map_err(Error::wrap(Error::Encode))
+}
+
+fn write_multitrack_pcm_data(
+    stems: &[(&str, &[u8])],
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+    output_path: &Path,
+) -> Result<()> {
+    let mut output_format_context = create_output_format_context(output_path)?;
+
+    if is_interleaved_container(&output_format_context) {
+        return encode_interleaved_pcm_data(
+            output_format_context,
+            stems,
+            pcm_audio_info,
+            audio_parameters,
+            options,
+            control,
+        );
+    }
+
+    let mut stream_encoders = stems
+        .iter()
+        .map(|(track_name, _)| {
+            StreamEncoder::new(
                 &mut output_format_context,
-                &mut encode_context,
-                Some(&output_frame),
+                pcm_audio_info,
+                audio_parameters,
+                options,
+                true,
+                Some(track_name),
+                0,
             )
-            .context("Write frame failed.")?;
+            .with_context(|| anyhow!("Create stream for {} failed.", track_name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    output_format_context
+        .write_header(&mut None)
+        .context("Write header failed.")?;
+
+    let stems: Vec<&[u8]> = stems.iter().map(|(_, pcm_data)| *pcm_data).collect();
+    encode_streams(
+        &mut output_format_context,
+        &mut stream_encoders,
+        pcm_audio_info,
+        &stems,
+        control,
+    )?;
+
+    output_format_context
+        .write_trailer()
+        .context("Write trailer failed.")?;
+
+    Ok(())
+}
+
+/// `nb_channels` channels in unspecified order, so that the stems' channels
+/// are not taken for surround speakers.
+fn unspecified_ch_layout(nb_channels: usize) -> AVChannelLayout {
+    let mut ch_layout: ffi::AVChannelLayout = unsafe { mem::zeroed() };
+    ch_layout.order = ffi::AV_CHANNEL_ORDER_UNSPEC;
+    ch_layout.nb_channels = nb_channels as i32;
+    AVChannelLayout::new(ch_layout)
+}
+
+fn encode_interleaved_pcm_data(
+    mut output_format_context: AVFormatContextOutput,
+    stems: &[(&str, &[u8])],
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+) -> Result<()> {
+    if options.ch_layout.is_some() {
+        bail!("Channel layout cannot be changed when interleaving stems.");
+    }
+    let stem_channels = pcm_audio_info.ch_layout.nb_channels as usize;
+    let nb_channels = stem_channels * stems.len();
+    let sample_size = pcm_audio_info.sample_size;
+    let num_samples = stems
+        .iter()
+        .map(|(_, pcm_data)| pcm_data.len() / sample_size / stem_channels)
+        .min()
+        .unwrap_or(0);
+
+    let mut pcm_data = Vec::with_capacity(num_samples * nb_channels * sample_size);
+    for i in 0..num_samples {
+        for (_, stem) in stems {
+            let begin = i * stem_channels * sample_size;
+            pcm_data.extend_from_slice(&stem[begin..begin + stem_channels * sample_size]);
         }
     }
 
-    write_frame(&mut output_format_context, &mut encode_context, None)
-        .context("Flush encode_context failed.")?;
+    let interleaved_audio_info = AudioInfo {
+        sample_rate: pcm_audio_info.sample_rate,
+        sample_fmt: pcm_audio_info.sample_fmt,
+        ch_layout: unspecified_ch_layout(nb_channels),
+        sample_size,
+    };
+    let interleaved_options = EncodeOptions {
+        codec_id: None,
+        sample_rate: options.sample_rate,
+        ch_layout: Some(unspecified_ch_layout(nb_channels)),
+        sample_fmt: options.sample_fmt,
+        bits_per_sample: options.bits_per_sample,
+        dither: options.dither,
+    };
+
+    let mut stream_encoder = StreamEncoder::new(
+        &mut output_format_context,
+        &interleaved_audio_info,
+        audio_parameters,
+        &interleaved_options,
+        false,
+        None,
+        0,
+    )?;
+
+    let channel_map = stems
+        .iter()
+        .enumerate()
+        .map(|(i, (track_name, _))| {
+            format!(
+                "{}={}-{}",
+                track_name,
+                i * stem_channels,
+                (i + 1) * stem_channels - 1
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let channel_map = CString::new(format!("rspleeter stems: {}", channel_map)).unwrap();
+    output_format_context.set_metadata(Some(AVDictionary::new(cstr!("comment"), &channel_map, 0)));
+
+    // Switch to RF64 automatically when the data outgrows plain WAV, W64 has
+    // no such limit.
+    let format_name = unsafe { CStr::from_ptr(output_format_context.oformat().name) };
+    let mut muxer_options = (format_name.to_bytes() == b"wav")
+        .then(|| AVDictionary::new(cstr!("rf64"), cstr!("auto"), 0));
+    output_format_context
+        .write_header(&mut muxer_options)
+        .context("Write header failed.")?;
+
+    encode_streams(
+        &mut output_format_context,
+        &mut [stream_encoder],
+        &interleaved_audio_info,
+        &[&pcm_data],
+        control,
+    )?;
 
     output_format_context
         .write_trailer()
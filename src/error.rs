@@ -0,0 +1,93 @@
+//! Error categories of the public API and their process exit codes.
+use rsmpeg::error::RsmpegError;
+use std::io;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// What went wrong, each variant keeps the detailed cause as its source.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The input cannot be found or opened, or has no audio stream.
+    #[error("Invalid input.")]
+    Input(#[source] BoxError),
+    /// The input is corrupt or uses a codec FFmpeg cannot decode.
+    #[error("Decode failed.")]
+    Decode(#[source] BoxError),
+    /// The model is missing, incomplete or cannot be loaded.
+    #[error("Model unavailable.")]
+    Model(#[source] BoxError),
+    /// Running the model or a stem processor failed.
+    #[error("Inference failed.")]
+    Inference(#[source] BoxError),
+    /// The output format or codec is unsupported, or encoding failed.
+    #[error("Encode failed.")]
+    Encode(#[source] BoxError),
+    /// Reading or writing files failed.
+    #[error("I/O failed.")]
+    Io(#[from] io::Error),
+    /// Cancelled through `control::Control`, or by dropping an async job.
+    #[error("Cancelled.")]
+    Cancelled,
+}
+
+/// FFmpeg reports failed file operations as `AVERROR(errno)`, these errnos
+/// share their values on every platform.
+fn io_error_kind(errno: i32) -> Option<io::ErrorKind> {
+    match errno {
+        // EPERM, EACCES
+        1 | 13 => Some(io::ErrorKind::PermissionDenied),
+        // EPIPE
+        32 => Some(io::ErrorKind::BrokenPipe),
+        // EIO, EFBIG, ENOSPC, EROFS
+        5 | 27 | 28 | 30 => Some(io::ErrorKind::Other),
+        _ => None,
+    }
+}
+
+impl Error {
+    /// Map an internal error into a category, keeping errors that already
+    /// have one.
+    pub(crate) fn wrap(kind: fn(BoxError) -> Error) -> impl Fn(anyhow::Error) -> Error {
+        move |e| {
+            let e = match e.downcast::<Error>() {
+                Ok(e) => return e,
+                Err(e) => e,
+            };
+            let io_error_kind = e
+                .chain()
+                .filter_map(|x| x.downcast_ref::<RsmpegError>())
+                .find_map(|x| io_error_kind(-x.raw_error()?));
+            match io_error_kind {
+                Some(io_error_kind) => Error::Io(io::Error::new(io_error_kind, BoxError::from(e))),
+                None => kind(e.into()),
+            }
+        }
+    }
+
+    /// Process exit code, check `doc/exit_codes.md`.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Input(_) => 3,
+            Error::Decode(_) => 4,
+            Error::Model(_) => 5,
+            Error::Inference(_) => 6,
+            Error::Encode(_) => 7,
+            Error::Io(_) => 8,
+            // 128 + SIGINT
+            Error::Cancelled => 130,
+        }
+    }
+}
+
+/// Exit code of any error, `1` when it has no category.
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    error
+        .chain()
+        .find_map(|e| e.downcast_ref::<Error>())
+        .map_or(1, Error::exit_code)
+}
+
+/// Whether `error` comes from a cancelled operation.
+pub fn is_cancelled(error: &anyhow::Error) -> bool {
+    exit_code(error) == Error::Cancelled.exit_code()
+}
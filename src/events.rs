@@ -0,0 +1,129 @@
+//! Machine-readable progress events, one JSON object per line (NDJSON).
+//!
+//! Check `doc/events.md` for the schema of each event.
+use camino::Utf8Path as Path;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::{
+    io::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Bumped on incompatible changes to any event.
+pub const EVENTS_VERSION: u32 = 1;
+
+/// Where events go, unset when events are disabled.
+static SINK: OnceCell<Mutex<Box<dyn Write + Send>>> = OnceCell::new();
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Decode,
+    Separate,
+    Encode,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    RunStarted {
+        version: u32,
+        model: &'a str,
+        inputs: usize,
+    },
+    StageStarted {
+        input: &'a Path,
+        stage: Stage,
+    },
+    StageFinished {
+        input: &'a Path,
+        stage: Stage,
+        seconds: f64,
+    },
+    /// Decode or encode progress.
+    Progress {
+        input: &'a Path,
+        stage: Stage,
+        /// Stem being encoded, when stems are encoded in parallel.
+        track: Option<&'a str>,
+        /// Seconds of audio done.
+        seconds: f64,
+        /// Seconds of audio in total, if known.
+        duration: Option<f64>,
+    },
+    Segment {
+        input: &'a Path,
+        /// 1-based.
+        segment: usize,
+        segments: usize,
+        /// Estimated seconds until separation of this input finishes.
+        eta_seconds: f64,
+    },
+    OutputWritten {
+        input: &'a Path,
+        path: &'a Path,
+        stems: Vec<&'a str>,
+    },
+    InputFinished {
+        input: &'a Path,
+        /// `processed`, `skipped` or `failed`.
+        status: &'a str,
+        error: Option<String>,
+        completed: usize,
+        total: usize,
+        /// Estimated seconds until every input is done.
+        eta_seconds: f64,
+    },
+    Warning {
+        input: Option<&'a Path>,
+        message: String,
+    },
+    RunFinished {
+        processed: usize,
+        skipped: usize,
+        failed: usize,
+        seconds: f64,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, 'b> {
+    /// Unix time in seconds.
+    time: f64,
+    #[serde(flatten)]
+    event: &'b Event<'a>,
+}
+
+/// Send events to `writer`, can only be called once.
+pub fn init(writer: Box<dyn Write + Send>) {
+    if SINK.set(Mutex::new(writer)).is_err() {
+        panic!("Event sink initialized twice.");
+    }
+}
+
+pub fn enabled() -> bool {
+    SINK.get().is_some()
+}
+
+/// Write `event` if events are enabled.
+pub fn emit(event: Event) {
+    let sink = match SINK.get() {
+        Some(sink) => sink,
+        None => return,
+    };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs_f64())
+        .unwrap_or_default();
+    let envelope = Envelope {
+        time,
+        event: &event,
+    };
+    // unwrap: events only hold strings and numbers.
+    let mut line = serde_json::to_vec(&envelope).unwrap();
+    line.push(b'\n');
+    let mut sink = sink.lock().unwrap();
+    // A consumer going away must not abort the run.
+    let _ = sink.write_all(&line).and_then(|_| sink.flush());
+}
@@ -0,0 +1,71 @@
+//! Expand input arguments (files, directories and glob patterns) into files.
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
+use std::collections::BTreeSet;
+
+use crate::error::Error;
+
+/// Extensions picked up from directories and glob patterns by default.
+pub const DEFAULT_EXTENSIONS: &[&str] = &[
+    "mp3", "flac", "wav", "m4a", "aac", "ogg", "opus", "wma", "mp4", "mkv", "mka", "webm", "mov",
+];
+
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .map(|extension| extensions.iter().any(|x| x.eq_ignore_ascii_case(extension)))
+        .unwrap_or(false)
+}
+
+fn is_glob_pattern(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+/// Collect files under `dir` recursively.
+fn walk_dir(dir: &Path, extensions: &[String], files: &mut BTreeSet<PathBuf>) -> Result<()> {
+    for entry in dir
+        .read_dir_utf8()
+        .with_context(|| anyhow!("Read dir {} failed.", dir))?
+    {
+        let entry = entry.with_context(|| anyhow!("Read dir {} failed.", dir))?;
+        let path = entry.path();
+        let file_type = entry
+            .file_type()
+            .with_context(|| anyhow!("Get file type of {} failed.", path))?;
+        if file_type.is_dir() {
+            walk_dir(path, extensions, files)?;
+        } else if has_extension(path, extensions) {
+            files.insert(path.to_owned());
+        }
+    }
+    Ok(())
+}
+
+/// Expand inputs into a sorted, deduplicated file list.
+///
+/// Files given explicitly are always kept, files found in directories or by
+/// glob patterns are filtered by `extensions`.
+pub fn collect_inputs(inputs: &[String], extensions: &[String]) -> Result<Vec<PathBuf>> {
+    let mut files = BTreeSet::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            walk_dir(path, extensions, &mut files)?;
+        } else if path.is_file() {
+            files.insert(path.to_owned());
+        } else if is_glob_pattern(input) {
+            for entry in glob::glob(input).with_context(|| anyhow!("Invalid pattern: {}", input))? {
+                let path = entry.with_context(|| anyhow!("Read {} failed.", input))?;
+                let path = PathBuf::from_path_buf(path)
+                    .map_err(|path| anyhow!("Non UTF-8 path: {}", path.display()))?;
+                if path.is_dir() {
+                    walk_dir(&path, extensions, &mut files)?;
+                } else if has_extension(&path, extensions) {
+                    files.insert(path);
+                }
+            }
+        } else {
+            return Err(Error::Input(format!("Input not found: {}", input).into()).into());
+        }
+    }
+    Ok(files.into_iter().collect())
+}
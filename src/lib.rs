@@ -0,0 +1,31 @@
+//! Split a song into vocals and accompaniments, taking advantage of machine
+//! learning.
+#[cfg(feature = "async")]
+pub mod async_api;
+pub mod config;
+pub mod control;
+pub mod decode;
+#[cfg(feature = "embed-models")]
+pub mod embedded;
+pub mod encode;
+pub mod error;
+pub mod events;
+pub mod inputs;
+pub mod manifest;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod models;
+pub mod ni_stems;
+pub mod output;
+pub mod probe;
+pub mod processors;
+pub mod remux;
+pub mod splitter;
+pub mod streaming;
+pub mod template;
+pub mod utils;
+
+pub use error::Error;
+
+#[cfg(not(any(feature = "tensorflow", feature = "mock")))]
+compile_error!("Enable the `tensorflow` feature, or `mock` for tests.");
@@ -1,89 +1,1380 @@
-mod decode;
-mod encode;
-mod splitter;
-mod utils;
-
-use std::fs;
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fmt,
+    fs::{self, File},
+    io::{self, Write},
+    process::ExitCode,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
 
-use anyhow::{Context, Result};
-use camino::Utf8PathBuf as PathBuf;
-use clap::Parser;
-use tracing::info;
+use anyhow::{anyhow, bail, Context, Result};
+use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
+use clap::{Args, Parser, Subcommand};
+use rsmpeg::{
+    avutil::AVChannelLayout,
+    ffi::{self},
+};
+use serde::Serialize;
+use tracing::{error, info};
 
-use crate::{
-    splitter::{existing_models, SpleeterModelInfo},
-    utils::{AudioData, AudioInfo},
+use rspleeter::{
+    config::{self, Config},
+    control::{CancellationToken, Control, Progress},
+    decode,
+    encode::{self, Dither, EncodeOptions},
+    error,
+    events::{self, Event, Stage},
+    inputs,
+    manifest::{self, InputRecord, InputStatus, Levels, Manifest, StemRecord, Timings},
+    models,
+    ni_stems::{self, NiLimiter, NiStemsCodec},
+    output::{self, OverwritePolicy},
+    probe::{self, ProbeInfo},
+    processors::StemProcessors,
+    remux,
+    splitter::{existing_models, Separator, SpleeterModelInfo},
+    template::{self, TemplateContext},
+    utils::{self, AudioData, AudioInfo, AudioParameters},
 };
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    input: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(flatten)]
+    split: SplitArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Split inputs into stems (default).
+    Split(SplitArgs),
+    /// Show streams of an input and the settings a split would use.
+    Probe(ProbeArgs),
+    /// Manage installed models.
+    Models(ModelsArgs),
+    /// Inspect the configuration.
+    Config(ConfigArgs),
+}
+
+#[derive(Args)]
+struct ModelsArgs {
+    #[clap(subcommand)]
+    command: ModelsCommand,
+    #[clap(long, short, global = true)]
+    models_dir: Option<PathBuf>,
+    /// Print JSON instead of text.
+    #[clap(long, global = true)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct ConfigArgs {
+    #[clap(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the effective configuration as TOML.
+    Show,
+}
+
+#[derive(Subcommand)]
+enum ModelsCommand {
+    /// List known models and whether they are installed.
+    List,
+    /// Show the signature, operations, stems and sample rate of a model.
+    Inspect {
+        #[clap(value_parser = model_names())]
+        name: String,
+    },
+    /// Check model files against the SHA-256 checksums in `SHA256SUMS`.
+    Verify {
+        /// Models to check, defaults to all.
+        #[clap(value_parser = model_names())]
+        names: Vec<String>,
+    },
+    /// Unpack a release archive (e.g. `models.zip`) into the models dir.
+    Install {
+        archive: PathBuf,
+        /// Expected SHA-256 of the archive, required unless it holds a
+        /// `SHA256SUMS` file.
+        #[clap(long)]
+        sha256: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct SplitArgs {
+    /// Input files, directories (searched recursively) or glob patterns.
+    #[clap(required = true, num_args = 1..)]
+    inputs: Vec<String>,
     out_dir: PathBuf,
-    #[clap(long, short, default_value = "2stems", value_parser = existing_models())]
-    model_name: String,
-    #[clap(long, short, default_value = "models/models")]
-    models_dir: PathBuf,
+    /// Extensions of files picked up from directories and glob patterns.
+    #[clap(long, value_delimiter = ',', default_values_t = default_extensions())]
+    extensions: Vec<String>,
+    /// Model to use, defaults to the configured one (`2stems`).
+    #[clap(long, short, value_parser = model_names())]
+    model_name: Option<String>,
+    /// Directory holding the models, defaults to the configured one
+    /// (`models/models`).
+    #[clap(long, short)]
+    models_dir: Option<PathBuf>,
+    /// Output container extension (e.g. flac, wav), defaults to the
+    /// configured one, the input's, or `mka` with `--multitrack`.
+    #[clap(long)]
+    format: Option<String>,
+    /// Write all stems into one `stems.<format>` file, one stream per stem
+    /// (MKA, MP4) or interleaved channels (WAV, W64).
+    #[clap(long)]
+    multitrack: bool,
+    /// Write a Native Instruments stem file `<input>.stem.mp4` for Traktor
+    /// and Mixxx.
+    #[clap(long, conflicts_with = "multitrack")]
+    ni_stems: bool,
+    /// Codec of the streams in the NI stem file.
+    #[clap(long, value_enum, default_value_t = NiStemsCodec::Aac)]
+    ni_stems_codec: NiStemsCodec,
+    /// Ceiling of the NI stem limiter in dB.
+    #[clap(long, default_value_t = -0.35, allow_hyphen_values = true)]
+    ni_limiter_ceiling: f32,
+    /// Disable the NI stem limiter.
+    #[clap(long)]
+    ni_no_limiter: bool,
+    /// Copy the input's video into `<input>.<stems>.<ext>` with the audio
+    /// replaced by the given stems mixed together, e.g. `accompaniment` or
+    /// `drums+bass+other`. The output keeps the input's container, the
+    /// configured format is ignored.
+    #[clap(long, conflicts_with_all = ["multitrack", "ni_stems", "format"])]
+    remux_video: Option<String>,
+    /// Output path template relative to `out_dir`, e.g.
+    /// `{input_stem}/{model}/{track}.{ext}`. Placeholders: `{input_stem}`,
+    /// `{input_name}`, `{model}`, `{track}`, `{index}`, `{ext}`, `{date}`,
+    /// `{hash}`.
+    #[clap(long)]
+    output_template: Option<String>,
+    #[clap(flatten)]
+    encode: EncodeArgs,
+    /// Replace existing outputs (default).
+    #[clap(long, group = "existing")]
+    overwrite: bool,
+    /// Keep existing outputs and don't write them again.
+    #[clap(long, group = "existing")]
+    skip_existing: bool,
+    /// Abort if any output already exists.
+    #[clap(long, group = "existing")]
+    fail_if_exists: bool,
+    /// Number of inputs waiting between the decode, separation and encode
+    /// stages.
+    #[clap(long)]
+    queue_depth: Option<usize>,
+    /// Number of stems encoded concurrently, defaults to the number of CPUs.
+    #[clap(long, short)]
+    jobs: Option<usize>,
+    /// Length of the segments fed to the model in seconds, longer segments
+    /// use more memory.
+    #[clap(long)]
+    segment_length: Option<usize>,
+    /// Context added on both sides of a segment in seconds.
+    #[clap(long)]
+    segment_overlap: Option<usize>,
+    /// Print FFmpeg's description of every input.
+    #[clap(long, short)]
+    verbose: bool,
+    /// Write `manifest.json` describing every output into `out_dir`.
+    #[clap(long)]
+    manifest: bool,
+    /// Write progress events, one JSON object per line, to stdout (logs move
+    /// to stderr) or to `--events-fd`. Check `doc/events.md` for the schema.
+    #[clap(long, value_enum)]
+    events: Option<EventsFormat>,
+    /// File descriptor events are written to instead of stdout.
+    #[clap(long, requires = "events")]
+    events_fd: Option<i32>,
+}
+
+/// Output audio format overrides, shared by `split` and `probe`.
+#[derive(Args)]
+struct EncodeArgs {
+    /// Output sample rate, defaults to the input's.
+    #[clap(long)]
+    sample_rate: Option<usize>,
+    /// Output channel layout (e.g. mono, stereo, 5.1), defaults to the input's.
+    #[clap(long)]
+    channel_layout: Option<String>,
+    /// Output sample format (e.g. s16, s32, flt), defaults to the input's.
+    #[clap(long, value_parser = parse_sample_fmt)]
+    sample_format: Option<ffi::AVSampleFormat>,
+    /// Output bit depth (e.g. 16, 24).
+    #[clap(long)]
+    bit_depth: Option<i32>,
+    /// Dither applied when quantizing to integer samples.
+    #[clap(long, value_enum, default_value_t = Dither::Tpdf)]
+    dither: Dither,
+}
+
+impl EncodeArgs {
+    fn encode_options(&self) -> Result<EncodeOptions> {
+        Ok(EncodeOptions {
+            codec_id: None,
+            sample_rate: self.sample_rate,
+            ch_layout: self
+                .channel_layout
+                .as_deref()
+                .map(parse_ch_layout)
+                .transpose()?,
+            sample_fmt: self.sample_format,
+            bits_per_sample: self.bit_depth,
+            dither: self.dither,
+        })
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum EventsFormat {
+    Json,
+}
+
+#[derive(Args)]
+struct ProbeArgs {
+    file: PathBuf,
+    /// Print JSON instead of text.
+    #[clap(long)]
+    json: bool,
+    #[clap(long, short, value_parser = model_names())]
+    model_name: Option<String>,
+    #[clap(long, short)]
+    models_dir: Option<PathBuf>,
+    /// Output container extension, defaults to the configured one or the
+    /// input's.
+    #[clap(long)]
+    format: Option<String>,
+    #[clap(flatten)]
+    encode: EncodeArgs,
+}
+
+impl SplitArgs {
+    /// Apply the flags given on the command line over `config`.
+    fn apply_to(&self, mut config: Config) -> Result<Config> {
+        if let Some(model_name) = &self.model_name {
+            config.model = model_name.clone();
+        }
+        if let Some(models_dir) = &self.models_dir {
+            config.models_dir = models_dir.clone();
+        }
+        if let Some(format) = &self.format {
+            config.format = Some(format.clone());
+        }
+        if let Some(queue_depth) = self.queue_depth {
+            config.queue_depth = queue_depth;
+        }
+        if let Some(jobs) = self.jobs {
+            config.jobs = jobs;
+        }
+        if let Some(segment_length) = self.segment_length {
+            config.segment_length = segment_length;
+        }
+        if let Some(segment_overlap) = self.segment_overlap {
+            config.segment_overlap = segment_overlap;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn overwrite_policy(&self) -> OverwritePolicy {
+        if self.skip_existing {
+            OverwritePolicy::SkipExisting
+        } else if self.fail_if_exists {
+            OverwritePolicy::FailIfExists
+        } else {
+            OverwritePolicy::Overwrite
+        }
+    }
+}
+
+/// Models installed in the configured models dir or embedded. Clap checks
+/// values before `--models-dir` is known, so it isn't taken into account.
+fn model_names() -> Vec<&'static str> {
+    existing_models(&Config::load().unwrap_or_default().models_dir)
+}
+
+fn default_extensions() -> Vec<String> {
+    inputs::DEFAULT_EXTENSIONS
+        .iter()
+        .map(|x| x.to_string())
+        .collect()
+}
+
+fn parse_ch_layout(s: &str) -> Result<AVChannelLayout> {
+    let name = CString::new(s)?;
+    AVChannelLayout::from_string(&name).with_context(|| anyhow!("Invalid channel layout: {}", s))
+}
+
+fn parse_sample_fmt(s: &str) -> Result<ffi::AVSampleFormat> {
+    let name = CString::new(s)?;
+    match unsafe { ffi::av_get_sample_fmt(name.as_ptr()) } {
+        ffi::AV_SAMPLE_FMT_NONE => Err(anyhow!("Invalid sample format: {}", s)),
+        sample_fmt => Ok(sample_fmt),
+    }
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
     let color = supports_color::on(supports_color::Stream::Stdout).is_some()
         && supports_color::on(supports_color::Stream::Stderr).is_some();
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .with_ansi(color)
-        .with_env_filter("info")
-        .init();
+        .with_env_filter("info");
+    let split_args = match &cli.command {
+        Some(Command::Split(args)) => Some(args),
+        None => Some(&cli.split),
+        _ => None,
+    };
+    // Keep stdout clean for events.
+    if split_args.map_or(false, |args| {
+        args.events.is_some() && args.events_fd.is_none()
+    }) {
+        subscriber.with_writer(io::stderr).init();
+    } else {
+        subscriber.init();
+    }
 
-    let cli = Cli::parse();
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(error::exit_code(&e))
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let config = Config::load().context("Load config failed.")?;
+    match cli.command {
+        Some(Command::Split(args)) => split(args, config),
+        Some(Command::Probe(args)) => probe_input(args, config),
+        Some(Command::Models(args)) => manage_models(args, config),
+        Some(Command::Config(args)) => show_config(args, config),
+        None => split(cli.split, config),
+    }
+}
+
+fn show_config(args: ConfigArgs, config: Config) -> Result<()> {
+    match args.command {
+        ConfigCommand::Show => {
+            if let Some(path) = config::config_path() {
+                let status = if path.is_file() { "" } else { " (not found)" };
+                println!("# Config file: {}{}", path, status);
+            }
+            if let Err(e) = config.validate() {
+                println!("# Invalid: {:#}", e);
+            }
+            print!(
+                "{}",
+                toml::to_string(&config).context("Serialize config failed.")?
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Result<File> {
+    use std::os::unix::io::FromRawFd;
+    if fd < 0 {
+        bail!("Invalid file descriptor: {}", fd);
+    }
+    // The caller hands the descriptor over to us for the rest of the process.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> Result<File> {
+    bail!("--events-fd is only supported on Unix.");
+}
+
+fn split(cli: SplitArgs, config: Config) -> Result<()> {
+    let config = cli.apply_to(config)?;
+    if let Some(EventsFormat::Json) = cli.events {
+        let writer: Box<dyn Write + Send> = match cli.events_fd {
+            Some(fd) => Box::new(open_fd(fd)?),
+            None => Box::new(io::stdout()),
+        };
+        events::init(writer);
+    }
     fs::create_dir_all(&cli.out_dir).context("Create output dir failed.")?;
+    let cancellation_token = CancellationToken::new();
+    output::install_interrupt_handler(cancellation_token.clone())?;
 
-    let pcm_sample_rate = 44100;
-    let audio_path = &cli.input;
-    let model_name = &cli.model_name;
+    let inputs =
+        inputs::collect_inputs(&cli.inputs, &cli.extensions).context("Collect inputs failed.")?;
+    if inputs.is_empty() {
+        bail!("No input files found.");
+    }
+
+    let encode_options = cli.encode.encode_options()?;
+
+    let model_info =
+        SpleeterModelInfo::get_by_name(&config.model).context("Cannot find model info")?;
+    if let Some(remux_stems) = &cli.remux_video {
+        for track_name in remux_stems.split('+') {
+            if !model_info.track_names.contains(&track_name) {
+                bail!(
+                    "Unknown stem {}, expected one of: {}",
+                    track_name,
+                    model_info.track_names.join(", ")
+                );
+            }
+        }
+    }
+
+    // Load the model once for every input.
+    let separator = Separator::load(model_info, &config.models_dir)?
+        .with_segmenting(config.segment_length, config.segment_overlap)
+        .with_processors(StemProcessors::from_config(&config.processors)?);
+
+    events::emit(Event::RunStarted {
+        version: events::EVENTS_VERSION,
+        model: model_info.name,
+        inputs: inputs.len(),
+    });
+
+    let batch = inputs.len() > 1;
+    // Render every output path up front, so that collisions are reported
+    // before anything is written.
+    let planned_outputs: Vec<_> = inputs
+        .iter()
+        .map(|input| plan_outputs(&cli, &config, model_info, input, batch))
+        .collect();
+    template::check_collisions(
+        inputs
+            .iter()
+            .zip(&planned_outputs)
+            .filter_map(|(input, output_paths)| {
+                Some((input.as_path(), output_paths.as_ref().ok()?))
+            }),
+    )?;
+
+    let start = Instant::now();
+    // Decode, separation and encode run on their own threads, so that input
+    // N + 1 is decoded and input N - 1 encoded while input N is separated.
+    // Bounded queues cap the number of inputs held in memory.
+    let (decoded_sender, decoded_receiver) = mpsc::sync_channel(config.queue_depth);
+    let (separated_sender, separated_receiver) = mpsc::sync_channel(config.queue_depth);
+    let outcomes: Vec<(&PathBuf, Result<Option<(Vec<StemRecord>, Timings)>>)> =
+        thread::scope(|s| {
+            let cli = &cli;
+            let config = &config;
+            let inputs = &inputs;
+            let cancellation_token = &cancellation_token;
+            s.spawn(move || {
+                for (i, (input, output_paths)) in inputs.iter().zip(planned_outputs).enumerate() {
+                    if cancellation_token.is_cancelled() {
+                        break;
+                    }
+                    info!("[{}/{}] Decoding: {}", i + 1, inputs.len(), input);
+                    let control = Control::new(cancellation_token.clone())
+                        .with_progress(progress_events(input, Stage::Decode));
+                    let decoded = output_paths
+                        .and_then(|output_paths| decode_input(cli, input, output_paths, &control));
+                    if decoded_sender.send((input, decoded)).is_err() {
+                        break;
+                    }
+                }
+            });
+            let encoder = s.spawn(move || {
+                let mut outcomes = Vec::new();
+                for (input, separated) in separated_receiver {
+                    let outcome = separated.and_then(|separated| match separated {
+                        Some((decoded, transformed_samples, separate_time)) => {
+                            let decode_time = decoded.decode_time;
+                            events::emit(Event::StageStarted {
+                                input,
+                                stage: Stage::Encode,
+                            });
+                            let start = Instant::now();
+                            let control = Control::new(cancellation_token.clone())
+                                .with_progress(progress_events(input, Stage::Encode));
+                            let records = write_outputs(
+                                cli,
+                                config,
+                                model_info,
+                                &encode_options,
+                                &control,
+                                decoded,
+                                transformed_samples,
+                            )?;
+                            events::emit(Event::StageFinished {
+                                input,
+                                stage: Stage::Encode,
+                                seconds: start.elapsed().as_secs_f64(),
+                            });
+                            let timings = Timings::new(decode_time, separate_time, start.elapsed());
+                            Ok(Some((records, timings)))
+                        }
+                        None => Ok(None),
+                    });
+                    let completed = outcomes.len() + 1;
+                    let elapsed = start.elapsed().as_secs_f64();
+                    events::emit(Event::InputFinished {
+                        input,
+                        status: match &outcome {
+                            Ok(Some(_)) => "processed",
+                            Ok(None) => "skipped",
+                            Err(_) => "failed",
+                        },
+                        error: outcome.as_ref().err().map(|e| format!("{:#}", e)),
+                        completed,
+                        total: inputs.len(),
+                        eta_seconds: elapsed / completed as f64 * (inputs.len() - completed) as f64,
+                    });
+                    outcomes.push((input, outcome));
+                }
+                outcomes
+            });
+            for (input, decoded) in decoded_receiver {
+                info!("Separating: {}", input);
+                let separated = decoded.and_then(|decoded| match decoded {
+                    Some(decoded) => {
+                        events::emit(Event::StageStarted {
+                            input,
+                            stage: Stage::Separate,
+                        });
+                        let start = Instant::now();
+                        let control = Control::new(cancellation_token.clone())
+                            .with_progress(progress_events(input, Stage::Separate));
+                        let transformed_samples = separator
+                            .separate_with_control(&decoded.audio_data, &control)
+                            .context("Split pcm audio failed.")?;
+                        events::emit(Event::StageFinished {
+                            input,
+                            stage: Stage::Separate,
+                            seconds: start.elapsed().as_secs_f64(),
+                        });
+                        Ok(Some((decoded, transformed_samples, start.elapsed())))
+                    }
+                    None => Ok(None),
+                });
+                if separated_sender.send((input, separated)).is_err() {
+                    break;
+                }
+            }
+            drop(separated_sender);
+            encoder.join().unwrap()
+        });
+    let elapsed = start.elapsed();
+
+    let mut processed = 0;
+    let mut skipped = 0;
+    let mut failed = Vec::new();
+    let mut records = Vec::new();
+    for (input, outcome) in outcomes {
+        let record = match outcome {
+            Ok(Some((stems, timings))) => {
+                processed += 1;
+                InputRecord {
+                    path: input.clone(),
+                    sha256: None,
+                    status: InputStatus::Processed,
+                    error: None,
+                    stems,
+                    timings,
+                }
+            }
+            Ok(None) => {
+                skipped += 1;
+                InputRecord {
+                    path: input.clone(),
+                    sha256: None,
+                    status: InputStatus::Skipped,
+                    error: None,
+                    stems: Vec::new(),
+                    timings: Timings::default(),
+                }
+            }
+            Err(e) => {
+                if !error::is_cancelled(&e) {
+                    error!("Process {} failed: {:?}", input, e);
+                }
+                let record = InputRecord {
+                    path: input.clone(),
+                    sha256: None,
+                    status: InputStatus::Failed,
+                    error: Some(format!("{:#}", e)),
+                    stems: e
+                        .downcast_ref::<StemsFailed>()
+                        .map_or_else(Vec::new, |x| x.records.clone()),
+                    timings: Timings::default(),
+                };
+                failed.push((input, e));
+                record
+            }
+        };
+        records.push(record);
+    }
+
+    if cli.manifest {
+        write_manifest(&cli.out_dir, model_info, records, elapsed)?;
+    }
+
+    events::emit(Event::RunFinished {
+        processed,
+        skipped,
+        failed: failed.len(),
+        seconds: elapsed.as_secs_f64(),
+    });
+
+    info!(
+        "Summary: {} processed, {} skipped, {} failed in {:.1?} ({:.2} files/min).",
+        processed,
+        skipped,
+        failed.len(),
+        elapsed,
+        processed as f64 / elapsed.as_secs_f64().max(f64::EPSILON) * 60.
+    );
+    if cancellation_token.is_cancelled() {
+        return Err(error::Error::Cancelled.into());
+    }
+    if !failed.is_empty() {
+        for (input, _) in &failed {
+            error!("Failed: {}", input);
+        }
+        let message = format!("{} of {} inputs failed.", failed.len(), inputs.len());
+        // Keep the exit code of the failures if they all agree.
+        let exit_code = error::exit_code(&failed[0].1);
+        if failed.iter().all(|(_, e)| error::exit_code(e) == exit_code) {
+            let (_, e) = failed.swap_remove(0);
+            return Err(e.context(message));
+        }
+        bail!(message);
+    }
+
+    Ok(())
+}
+
+/// Progress callback turning progress of `input` into events.
+fn progress_events(input: &Path, stage: Stage) -> impl Fn(Progress) + Send + Sync + 'static {
+    let input = input.to_owned();
+    let start = Instant::now();
+    move |progress| match progress {
+        Progress::Decode { seconds, duration } => events::emit(Event::Progress {
+            input: &input,
+            stage,
+            track: None,
+            seconds,
+            duration,
+        }),
+        Progress::Encode {
+            track,
+            seconds,
+            duration,
+        } => events::emit(Event::Progress {
+            input: &input,
+            stage,
+            track: track.as_deref(),
+            seconds,
+            duration: Some(duration),
+        }),
+        Progress::Segment { segment, segments } => {
+            let elapsed = start.elapsed().as_secs_f64();
+            events::emit(Event::Segment {
+                input: &input,
+                segment,
+                segments,
+                eta_seconds: elapsed / segment as f64 * (segments - segment) as f64,
+            })
+        }
+    }
+}
+
+/// Checksum inputs and outputs, then write `manifest.json`.
+fn write_manifest(
+    out_dir: &Path,
+    model_info: &SpleeterModelInfo,
+    mut inputs: Vec<InputRecord>,
+    elapsed: Duration,
+) -> Result<()> {
+    // Multi-stream outputs hold several stems, hash each file once.
+    let mut checksums: HashMap<PathBuf, String> = HashMap::new();
+    for input in &mut inputs {
+        input.sha256 = Some(utils::sha256_file(&input.path)?);
+        for stem in &mut input.stems {
+            if !stem.path.is_file() {
+                continue;
+            }
+            let checksum = match checksums.get(&stem.path) {
+                Some(checksum) => checksum.clone(),
+                None => {
+                    let checksum = utils::sha256_file(&stem.path)?;
+                    checksums.insert(stem.path.clone(), checksum.clone());
+                    checksum
+                }
+            };
+            stem.sha256 = Some(checksum);
+        }
+    }
+    // Stages overlap across inputs, so the total is the wall clock time of
+    // the run rather than the sum of the stages.
+    let timings = Timings {
+        decode: inputs.iter().map(|x| x.timings.decode).sum(),
+        separate: inputs.iter().map(|x| x.timings.separate).sum(),
+        encode: inputs.iter().map(|x| x.timings.encode).sum(),
+        total: elapsed.as_secs_f64(),
+    };
+    let manifest = Manifest {
+        version: manifest::MANIFEST_VERSION,
+        model: model_info.name.to_owned(),
+        inputs,
+        timings,
+    };
+    manifest::write(&out_dir.join("manifest.json"), &manifest)
+}
+
+/// An input decoded and ready for separation.
+struct DecodedInput {
+    audio_path: PathBuf,
+    output_paths: Vec<PathBuf>,
+    pcm_audio_info: AudioInfo,
+    original_audio_parameters: AudioParameters,
+    pcm_data: Vec<u8>,
+    audio_data: AudioData,
+    decode_time: Duration,
+}
+
+/// Render the output paths of one input.
+fn plan_outputs(
+    cli: &SplitArgs,
+    config: &Config,
+    model_info: &SpleeterModelInfo,
+    audio_path: &Path,
+    batch: bool,
+) -> Result<Vec<PathBuf>> {
+    let model_name = model_info.name;
     let out_dir = &cli.out_dir;
-    let audio_extension = audio_path
-        .extension()
-        .context("Audio path with no extension")?;
+    let audio_extension = match &config.format {
+        Some(format) => format.as_str(),
+        None if cli.multitrack => "mka",
+        None => audio_path
+            .extension()
+            .context("Audio path with no extension")?,
+    };
+    // (default template, output names, extension)
+    let (default_template, output_names, output_extension) = if cli.ni_stems {
+        ("{input_stem}.{track}.{ext}", vec!["stem"], "mp4")
+    } else if let Some(remux_stems) = &cli.remux_video {
+        let extension = audio_path
+            .extension()
+            .context("Audio path with no extension")?;
+        (
+            "{input_stem}.{track}.{ext}",
+            vec![remux_stems.as_str()],
+            extension,
+        )
+    } else if cli.multitrack {
+        (
+            // Keep outputs of different inputs apart.
+            if batch {
+                "{input_stem}/{track}.{ext}"
+            } else {
+                "{track}.{ext}"
+            },
+            vec!["stems"],
+            audio_extension,
+        )
+    } else {
+        (
+            if batch {
+                "{input_stem}/{track}.{ext}"
+            } else {
+                "{track}.{ext}"
+            },
+            model_info.track_names.clone(),
+            audio_extension,
+        )
+    };
+    let output_template = cli.output_template.as_deref().unwrap_or(default_template);
+    let template_context = TemplateContext::new(audio_path, model_name, output_template)?;
+    output_names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            template::render(
+                output_template,
+                &template_context,
+                out_dir,
+                name,
+                i,
+                output_extension,
+            )
+        })
+        .collect()
+}
+
+/// Decode one input, returns `None` if all of its outputs are skipped.
+fn decode_input(
+    cli: &SplitArgs,
+    audio_path: &Path,
+    output_paths: Vec<PathBuf>,
+    control: &Control,
+) -> Result<Option<DecodedInput>> {
+    let pcm_sample_rate = 44100;
+    if !output::check_existing(&output_paths, cli.overwrite_policy())? {
+        info!("All outputs exist, skipping: {}", audio_path);
+        events::emit(Event::Warning {
+            input: Some(audio_path),
+            message: "All outputs exist, skipping.".to_owned(),
+        });
+        return Ok(None);
+    }
 
     let pcm_audio_info = AudioInfo::new_pcm(pcm_sample_rate);
 
+    events::emit(Event::StageStarted {
+        input: audio_path,
+        stage: Stage::Decode,
+    });
+    let start = Instant::now();
     let (original_audio_parameters, pcm_data) =
-        decode::decode_audio(audio_path, &pcm_audio_info).context("Decode audio failed.")?;
+        decode::decode_audio(audio_path, &pcm_audio_info, cli.verbose, control)
+            .context("Decode audio failed.")?;
+    events::emit(Event::StageFinished {
+        input: audio_path,
+        stage: Stage::Decode,
+        seconds: start.elapsed().as_secs_f64(),
+    });
 
-    let samples = pcm_data
-        .chunks_exact(4)
-        .map(|x| x.try_into().unwrap())
-        .map(f32::from_le_bytes)
-        .collect();
-    let audio_data = AudioData::new(
-        samples,
-        pcm_audio_info.ch_layout.nb_channels as usize,
-        pcm_audio_info.sample_rate,
-    );
+    let audio_data = AudioData::from_pcm(&pcm_data, &pcm_audio_info);
 
-    let model_info =
-        SpleeterModelInfo::get_by_name(model_name).context("Cannot find model info")?;
+    Ok(Some(DecodedInput {
+        audio_path: audio_path.to_owned(),
+        output_paths,
+        pcm_audio_info,
+        original_audio_parameters,
+        pcm_data,
+        audio_data,
+        decode_time: start.elapsed(),
+    }))
+}
 
-    let transformed_samples = splitter::split_pcm_audio(&audio_data, model_info, &cli.models_dir)
-        .context("Split pcm audio failed.")?;
+/// Write every output of a separated input.
+fn write_outputs(
+    cli: &SplitArgs,
+    config: &Config,
+    model_info: &SpleeterModelInfo,
+    encode_options: &EncodeOptions,
+    control: &Control,
+    decoded: DecodedInput,
+    transformed_samples: Vec<Vec<f32>>,
+) -> Result<Vec<StemRecord>> {
+    let DecodedInput {
+        audio_path,
+        output_paths,
+        pcm_audio_info,
+        original_audio_parameters,
+        pcm_data,
+        audio_data,
+        ..
+    } = decoded;
+    let audio_path = audio_path.as_path();
+    let overwrite_policy = cli.overwrite_policy();
+    // Kept outputs may differ from the fresh samples, only written ones get
+    // levels.
+    let stem_record =
+        |name: &str, path: &Path, samples: &[f32], options: &EncodeOptions, written: bool| {
+            let output = path.extension().and_then(|extension| {
+                encode::describe_output(&original_audio_parameters, options, extension).ok()
+            });
+            StemRecord {
+                written,
+                levels: written.then(|| {
+                    Levels::new(
+                        samples,
+                        pcm_audio_info.ch_layout.nb_channels as usize,
+                        pcm_audio_info.sample_rate,
+                    )
+                }),
+                ..StemRecord::new(name, path, output)
+            }
+        };
+
+    if cli.ni_stems {
+        let output_path = &output_paths[0];
+        let slots = ni_stems::map_to_slots(
+            &model_info.track_names,
+            &transformed_samples,
+            audio_data.samples.len(),
+        );
+        let limiter = NiLimiter {
+            enabled: !cli.ni_no_limiter,
+            ceiling: cli.ni_limiter_ceiling,
+            ..Default::default()
+        };
+        let metadata = ni_stems::stem_metadata(&slots, &limiter);
+        let encode_options = EncodeOptions {
+            codec_id: Some(cli.ni_stems_codec.codec_id()),
+            ..encode_options.clone()
+        };
+        let slots_pcm_data: Vec<(&str, Vec<u8>)> = slots
+            .iter()
+            .map(|slot| {
+                let pcm_data = slot
+                    .samples
+                    .iter()
+                    .map(|x| x.to_le_bytes())
+                    .flatten()
+                    .collect();
+                (slot.name.as_str(), pcm_data)
+            })
+            .collect();
+        let slots_pcm_data: Vec<(&str, &[u8])> = slots_pcm_data
+            .iter()
+            .map(|(name, pcm_data)| (*name, pcm_data.as_slice()))
+            .collect();
+        let written = output::write_atomically(output_path, overwrite_policy, |output_path| {
+            encode::encode_ni_stems(
+                &pcm_data,
+                &slots_pcm_data,
+                &metadata,
+                &pcm_audio_info,
+                &original_audio_parameters,
+                &encode_options,
+                control,
+                output_path,
+            )
+            .context("Encode NI stems failed.")
+        })?;
+        let mut records = vec![stem_record(
+            "mix",
+            output_path,
+            &audio_data.samples,
+            &encode_options,
+            written,
+        )];
+        records.extend(slots.iter().map(|slot| {
+            stem_record(
+                &slot.name,
+                output_path,
+                &slot.samples,
+                &encode_options,
+                written,
+            )
+        }));
+        emit_output_written(audio_path, &records);
+        return Ok(records);
+    }
 
-    for (track_name, pcm_data) in model_info
+    if let Some(remux_stems) = &cli.remux_video {
+        let output_path = &output_paths[0];
+        let mut samples = vec![0f32; audio_data.samples.len()];
+        for (track_name, track) in model_info.track_names.iter().zip(&transformed_samples) {
+            if remux_stems.split('+').any(|x| x == *track_name) {
+                for (sample, x) in samples.iter_mut().zip(track) {
+                    *sample += x;
+                }
+            }
+        }
+        let pcm_data: Vec<u8> = samples.iter().map(|x| x.to_le_bytes()).flatten().collect();
+        let written = output::write_atomically(output_path, overwrite_policy, |output_path| {
+            remux::remux_with_audio(
+                audio_path,
+                &pcm_data,
+                &pcm_audio_info,
+                &original_audio_parameters,
+                encode_options,
+                control,
+                output_path,
+            )
+            .context("Remux video failed.")
+        })?;
+        let records = vec![stem_record(
+            remux_stems,
+            output_path,
+            &samples,
+            encode_options,
+            written,
+        )];
+        emit_output_written(audio_path, &records);
+        return Ok(records);
+    }
+
+    let stems: Vec<(&str, Vec<u8>)> = model_info
         .track_names
         .iter()
         .cloned()
-        .zip(transformed_samples.into_iter())
+        .zip(&transformed_samples)
+        .map(|(track_name, pcm_data)| {
+            let pcm_data = pcm_data.iter().map(|x| x.to_le_bytes()).flatten().collect();
+            (track_name, pcm_data)
+        })
+        .collect();
+
+    if cli.multitrack {
+        let output_path = &output_paths[0];
+        let stems: Vec<(&str, &[u8])> = stems
+            .iter()
+            .map(|(track_name, pcm_data)| (*track_name, pcm_data.as_slice()))
+            .collect();
+        let written = output::write_atomically(output_path, overwrite_policy, |output_path| {
+            encode::encode_multitrack_pcm_data(
+                &stems,
+                &pcm_audio_info,
+                &original_audio_parameters,
+                encode_options,
+                control,
+                output_path,
+            )
+            .context("Encode multitrack pcm data failed.")
+        })?;
+        let records: Vec<_> = model_info
+            .track_names
+            .iter()
+            .zip(&transformed_samples)
+            .map(|(track_name, samples)| {
+                stem_record(track_name, output_path, samples, encode_options, written)
+            })
+            .collect();
+        emit_output_written(audio_path, &records);
+        return Ok(records);
+    }
+
+    // FFmpeg objects are not shared between threads, every job gets its own
+    // copies.
+    let encode_jobs: Vec<_> = stems
+        .iter()
+        .zip(&output_paths)
+        .map(|((track_name, pcm_data), output_path)| {
+            (
+                *track_name,
+                pcm_data.as_slice(),
+                output_path,
+                pcm_audio_info.clone(),
+                original_audio_parameters.clone(),
+                encode_options.clone(),
+            )
+        })
+        .collect();
+    let results = utils::parallel_map(
+        encode_jobs,
+        config.jobs,
+        |(track_name, pcm_data, output_path, pcm_audio_info, audio_parameters, encode_options)| {
+            let result = output::write_atomically(output_path, overwrite_policy, |output_path| {
+                encode::encode_pcm_data(
+                    pcm_data,
+                    &pcm_audio_info,
+                    &audio_parameters,
+                    &encode_options,
+                    &control.for_track(track_name),
+                    output_path,
+                )
+                .context("Encode pcm data failed.")
+            });
+            (track_name, result)
+        },
+    );
+
+    let mut failed = Vec::new();
+    let mut first_error = None;
+    let mut records = Vec::new();
+    for (((track_name, result), samples), output_path) in results
+        .into_iter()
+        .zip(&transformed_samples)
+        .zip(&output_paths)
     {
-        let output_path = out_dir.join(format!("{}.{}", track_name, audio_extension));
-        info!("Writing: {}", output_path);
-        let pcm_data: Vec<u8> = pcm_data.iter().map(|x| x.to_le_bytes()).flatten().collect();
-        // std::fs::write(output_path, &sample_data).context("Write pcm file failed.")?;
-        encode::encode_pcm_data(
-            &pcm_data,
-            &pcm_audio_info,
-            &original_audio_parameters,
-            &output_path,
-        )
-        .context("Encode pcm data failed.")?;
+        match result {
+            Ok(written) => records.push(stem_record(
+                track_name,
+                output_path,
+                samples,
+                encode_options,
+                written,
+            )),
+            Err(e) => {
+                error!("Encode {} failed: {:?}", track_name, e);
+                records.push(StemRecord {
+                    error: Some(format!("{:#}", e)),
+                    ..stem_record(track_name, output_path, samples, encode_options, false)
+                });
+                failed.push(track_name.to_owned());
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    emit_output_written(audio_path, &records);
+    if let Some(e) = first_error {
+        // The first error keeps its category for the exit code.
+        return Err(e.context(StemsFailed { failed, records }));
+    }
+
+    Ok(records)
+}
+
+/// Some stems of an input failed, `records` describes all of them.
+#[derive(Debug)]
+struct StemsFailed {
+    failed: Vec<String>,
+    records: Vec<StemRecord>,
+}
+
+impl fmt::Display for StemsFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Encode stems failed: {}", self.failed.join(", "))
+    }
+}
+
+/// Emit `output_written` once per written file.
+fn emit_output_written(input: &Path, records: &[StemRecord]) {
+    let mut paths: Vec<&Path> = Vec::new();
+    for record in records.iter().filter(|record| record.written) {
+        if !paths.contains(&record.path.as_path()) {
+            paths.push(&record.path);
+        }
+    }
+    for path in paths {
+        events::emit(Event::OutputWritten {
+            input,
+            path,
+            stems: records
+                .iter()
+                .filter(|record| record.path == path)
+                .map(|record| record.name.as_str())
+                .collect(),
+        });
     }
+}
+
+/// What a split of the probed input would use.
+#[derive(Serialize)]
+struct SplitSettings {
+    model: String,
+    models_dir: PathBuf,
+    model_installed: bool,
+    model_embedded: bool,
+    tracks: Vec<&'static str>,
+    /// Sample rate the model runs at.
+    pcm_sample_rate: usize,
+    output: Option<encode::OutputDescription>,
+}
 
+#[derive(Serialize)]
+struct ProbeReport {
+    input: ProbeInfo,
+    split: SplitSettings,
+}
+
+fn probe_input(args: ProbeArgs, config: Config) -> Result<()> {
+    let (input, audio_parameters) = probe::probe(&args.file).context("Probe input failed.")?;
+    let model_name = args.model_name.as_ref().unwrap_or(&config.model);
+    let models_dir = args.models_dir.as_ref().unwrap_or(&config.models_dir);
+    let model_info =
+        SpleeterModelInfo::get_by_name(model_name).context("Cannot find model info")?;
+    let pcm_audio_info = AudioInfo::new_pcm(44100);
+    let output = match &audio_parameters {
+        Some(audio_parameters) => {
+            let extension = match args.format.as_ref().or(config.format.as_ref()) {
+                Some(format) => format.as_str(),
+                None => args
+                    .file
+                    .extension()
+                    .context("Audio path with no extension")?,
+            };
+            Some(encode::describe_output(
+                audio_parameters,
+                &args.encode.encode_options()?,
+                extension,
+            )?)
+        }
+        None => None,
+    };
+    let report = ProbeReport {
+        input,
+        split: SplitSettings {
+            model: model_info.name.to_owned(),
+            models_dir: models_dir.clone(),
+            model_installed: models_dir.join(model_info.name).is_dir(),
+            model_embedded: models::is_embedded(model_info.name),
+            tracks: model_info.track_names.clone(),
+            pcm_sample_rate: pcm_audio_info.sample_rate,
+            output,
+        },
+    };
+
+    if args.json {
+        return print_json(&report);
+    }
+
+    let ProbeReport { input, split } = report;
+    println!("Input: {}", args.file);
+    println!(
+        "  Container: {} ({})",
+        input.format_name, input.format_long_name
+    );
+    if let Some(duration) = input.duration {
+        println!("  Duration: {:.3} s", duration);
+    }
+    if let Some(bit_rate) = input.bit_rate {
+        println!("  Bit rate: {} b/s", bit_rate);
+    }
+    for (key, value) in &input.tags {
+        println!("  {}: {}", key, value);
+    }
+    for stream in &input.streams {
+        let best = if input.best_audio_stream == Some(stream.index) {
+            " (split)"
+        } else {
+            ""
+        };
+        print!(
+            "  Stream #{}: {} {}",
+            stream.index, stream.codec_type, stream.codec_name
+        );
+        if let (Some(sample_rate), Some(channel_layout), Some(sample_format)) = (
+            stream.sample_rate,
+            &stream.channel_layout,
+            &stream.sample_format,
+        ) {
+            print!(
+                ", {} Hz, {}, {}",
+                sample_rate, channel_layout, sample_format
+            );
+        }
+        println!("{}", best);
+        for (key, value) in &stream.tags {
+            println!("    {}: {}", key, value);
+        }
+    }
+    println!("Split:");
+    println!(
+        "  Model: {} ({}){}",
+        split.model,
+        split.tracks.join(", "),
+        if split.model_installed {
+            ""
+        } else if split.model_embedded {
+            " [embedded]"
+        } else {
+            " [not installed]"
+        }
+    );
+    println!("  Models dir: {}", split.models_dir);
+    println!("  Separation sample rate: {} Hz", split.pcm_sample_rate);
+    match &split.output {
+        Some(output) => println!(
+            "  Output: {} {}, {} Hz, {}, {}",
+            output.container,
+            output.codec,
+            output.sample_rate,
+            output.channel_layout,
+            output.sample_format
+        ),
+        None => println!("  Output: none, no audio stream"),
+    }
+    Ok(())
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).context("Serialize JSON failed.")?
+    );
+    Ok(())
+}
+
+fn manage_models(args: ModelsArgs, config: Config) -> Result<()> {
+    let models_dir = args.models_dir.as_ref().unwrap_or(&config.models_dir);
+    match args.command {
+        ModelsCommand::List => {
+            let models = models::list(models_dir);
+            if args.json {
+                return print_json(&models);
+            }
+            for model in models {
+                println!(
+                    "{:<8} {:<10} {}",
+                    model.name,
+                    if model.installed {
+                        "installed"
+                    } else if model.embedded {
+                        "embedded"
+                    } else {
+                        "missing"
+                    },
+                    model.tracks.join(", ")
+                );
+            }
+        }
+        ModelsCommand::Inspect { name } => {
+            let model_info =
+                SpleeterModelInfo::get_by_name(&name).context("Cannot find model info")?;
+            let details =
+                models::inspect(model_info, models_dir).context("Inspect model failed.")?;
+            if args.json {
+                return print_json(&details);
+            }
+            println!("Model: {}", details.name);
+            println!("  Path: {}", details.path);
+            println!("  Stems: {}", details.tracks.join(", "));
+            println!("  Sample rate: {} Hz", details.sample_rate);
+            for signature in &details.signatures {
+                println!(
+                    "  Signature {} ({}):",
+                    signature.name, signature.method_name
+                );
+                for (direction, tensors) in
+                    [("input", &signature.inputs), ("output", &signature.outputs)]
+                {
+                    for tensor in tensors {
+                        println!(
+                            "    {} {}: {} {} {}",
+                            direction, tensor.key, tensor.tensor, tensor.dtype, tensor.shape
+                        );
+                    }
+                }
+            }
+            println!(
+                "  Operations: {}",
+                details.operations.values().sum::<usize>()
+            );
+            for (op_type, count) in &details.operations {
+                println!("    {}: {}", op_type, count);
+            }
+        }
+        ModelsCommand::Verify { names } => {
+            let names: Vec<&str> = names.iter().map(|x| x.as_str()).collect();
+            let mismatches = models::verify(models_dir, &names).context("Verify models failed.")?;
+            if args.json {
+                print_json(&mismatches)?;
+            } else {
+                for mismatch in &mismatches {
+                    match &mismatch.actual {
+                        Some(actual) => println!(
+                            "MISMATCH {}: expected {}, got {}",
+                            mismatch.path, mismatch.expected, actual
+                        ),
+                        None => println!("MISSING {}", mismatch.path),
+                    }
+                }
+            }
+            if !mismatches.is_empty() {
+                bail!("{} model files failed verification.", mismatches.len());
+            }
+            if !args.json {
+                println!("All model files OK.");
+            }
+        }
+        ModelsCommand::Install { archive, sha256 } => {
+            let names = models::install(&archive, models_dir, sha256.as_deref())
+                .context("Install models failed.")?;
+            if args.json {
+                return print_json(&names);
+            }
+            println!("Installed into {}: {}", models_dir, names.join(", "));
+        }
+    }
     Ok(())
 }
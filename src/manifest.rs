@@ -0,0 +1,140 @@
+//! `manifest.json` describing the outputs of a run.
+use anyhow::{Context, Result};
+use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
+use serde::Serialize;
+use std::{fs, time::Duration};
+
+use crate::encode::OutputDescription;
+use crate::output::{self, OverwritePolicy};
+
+/// Bumped on incompatible changes to the layout.
+pub const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub model: String,
+    pub inputs: Vec<InputRecord>,
+    pub timings: Timings,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputStatus {
+    Processed,
+    /// Every output already existed.
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InputRecord {
+    pub path: PathBuf,
+    pub sha256: Option<String>,
+    pub status: InputStatus,
+    pub error: Option<String>,
+    pub stems: Vec<StemRecord>,
+    pub timings: Timings,
+}
+
+/// One stem, several stems share a path in multi-stream outputs.
+#[derive(Debug, Clone, Serialize)]
+pub struct StemRecord {
+    pub name: String,
+    pub path: PathBuf,
+    /// False if an existing output was kept or encoding failed.
+    pub written: bool,
+    pub sha256: Option<String>,
+    pub output: Option<OutputDescription>,
+    /// Levels of the written audio, `null` unless `written`.
+    pub levels: Option<Levels>,
+    /// Why the stem was not written, with the other stems of the input kept.
+    pub error: Option<String>,
+}
+
+/// Duration and levels of a written stem.
+#[derive(Debug, Clone, Serialize)]
+pub struct Levels {
+    /// Duration in seconds.
+    pub duration: f64,
+    /// Samples per channel, at the separation sample rate.
+    pub samples: usize,
+    pub sample_rate: usize,
+    /// Peak sample magnitude in dBFS, `null` for silence.
+    pub peak_db: f64,
+    /// RMS level in dBFS, `null` for silence.
+    pub rms_db: f64,
+}
+
+/// Wall clock time of each stage in seconds.
+#[derive(Debug, Default, Serialize)]
+pub struct Timings {
+    pub decode: f64,
+    pub separate: f64,
+    pub encode: f64,
+    pub total: f64,
+}
+
+impl Timings {
+    pub fn new(decode: Duration, separate: Duration, encode: Duration) -> Self {
+        Self {
+            decode: decode.as_secs_f64(),
+            separate: separate.as_secs_f64(),
+            encode: encode.as_secs_f64(),
+            total: (decode + separate + encode).as_secs_f64(),
+        }
+    }
+}
+
+fn to_db(x: f64) -> f64 {
+    if x > 0. {
+        20. * x.log10()
+    } else {
+        f64::NEG_INFINITY
+    }
+}
+
+impl StemRecord {
+    /// Record of an output at `path` that was not written yet.
+    pub fn new(name: &str, path: &Path, output: Option<OutputDescription>) -> Self {
+        Self {
+            name: name.to_owned(),
+            path: path.to_owned(),
+            written: false,
+            sha256: None,
+            output,
+            levels: None,
+            error: None,
+        }
+    }
+}
+
+impl Levels {
+    /// Levels of `samples` (interleaved).
+    pub fn new(samples: &[f32], nb_channels: usize, sample_rate: usize) -> Self {
+        let mut peak = 0f32;
+        let mut sum_squares = 0f64;
+        for x in samples {
+            peak = peak.max(x.abs());
+            sum_squares += (*x as f64) * (*x as f64);
+        }
+        let rms = (sum_squares / samples.len().max(1) as f64).sqrt();
+        let frames = samples.len() / nb_channels.max(1);
+        Self {
+            duration: frames as f64 / sample_rate as f64,
+            samples: frames,
+            sample_rate,
+            peak_db: to_db(peak as f64),
+            rms_db: to_db(rms),
+        }
+    }
+}
+
+/// Write `manifest` to `path`, replacing any previous one.
+pub fn write(path: &Path, manifest: &Manifest) -> Result<()> {
+    let json = serde_json::to_string_pretty(manifest).context("Serialize manifest failed.")?;
+    output::write_atomically(path, OverwritePolicy::Overwrite, |path| {
+        fs::write(path, json).context("Write manifest failed.")
+    })?;
+    Ok(())
+}
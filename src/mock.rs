@@ -0,0 +1,155 @@
+//! Deterministic stand-in for the TensorFlow models, for tests only.
+//!
+//! Only built with the `mock` feature. `Separator::load` uses it when
+//! `RSPLEETER_MOCK_BACKEND` is set, so the CLI can be tested end to end
+//! without libtensorflow or model files:
+//!
+//! - `identity`: every stem is the input.
+//! - `gains=1,0.5`: stem `i` is the input scaled by the `i`th gain, 0 if
+//!   missing.
+//! - `bands=500,4000`: stems are adjacent frequency bands split at these
+//!   frequencies in Hz, summing up to the input.
+//! - `delay=50`: like `identity`, taking 50 ms per segment, for testing
+//!   cancellation.
+use anyhow::{anyhow, bail, Context, Result};
+use std::{env, f32::consts::PI, fmt, str::FromStr, thread, time::Duration};
+
+/// Environment variable selecting the mock backend.
+pub const MOCK_BACKEND_ENV: &str = "RSPLEETER_MOCK_BACKEND";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockBehavior {
+    Identity,
+    Gains(Vec<f32>),
+    /// Ascending crossover frequencies in Hz.
+    Bands(Vec<f32>),
+    /// Time taken by every segment.
+    Delay(Duration),
+}
+
+impl FromStr for MockBehavior {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse_list = |list: &str| -> Result<Vec<f32>> {
+            list.split(',')
+                .map(|x| {
+                    x.trim()
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid number: {}", x))
+                })
+                .collect()
+        };
+        match s.split_once('=') {
+            None if s == "identity" => Ok(MockBehavior::Identity),
+            Some(("gains", gains)) => Ok(MockBehavior::Gains(parse_list(gains)?)),
+            Some(("bands", bands)) => {
+                let bands = parse_list(bands)?;
+                if bands.windows(2).any(|x| x[0] >= x[1]) {
+                    bail!("Crossover frequencies must be ascending.");
+                }
+                Ok(MockBehavior::Bands(bands))
+            }
+            Some(("delay", millis)) => millis
+                .trim()
+                .parse()
+                .map(|millis| MockBehavior::Delay(Duration::from_millis(millis)))
+                .map_err(|_| anyhow!("Invalid milliseconds: {}", millis)),
+            _ => bail!(
+                "Expected `identity`, `gains=<list>`, `bands=<list>` or `delay=<ms>`: {}",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for MockBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |list: &[f32]| {
+            list.iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        match self {
+            MockBehavior::Identity => write!(f, "identity"),
+            MockBehavior::Gains(gains) => write!(f, "gains={}", join(gains)),
+            MockBehavior::Bands(bands) => write!(f, "bands={}", join(bands)),
+            MockBehavior::Delay(delay) => write!(f, "delay={}", delay.as_millis()),
+        }
+    }
+}
+
+/// Behavior requested by `RSPLEETER_MOCK_BACKEND`, if set.
+pub fn from_env() -> Result<Option<MockBehavior>> {
+    match env::var(MOCK_BACKEND_ENV) {
+        Ok(behavior) => behavior
+            .parse()
+            .map(Some)
+            .with_context(|| anyhow!("Invalid {}.", MOCK_BACKEND_ENV)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// One-pole low-pass of every channel of interleaved `input` at
+/// `sample_rate`.
+fn low_pass(input: &[f32], nb_channels: usize, sample_rate: usize, cutoff_hz: f32) -> Vec<f32> {
+    let a = (-2. * PI * cutoff_hz / sample_rate as f32).exp();
+    let mut state = vec![0f32; nb_channels];
+    input
+        .chunks(nb_channels)
+        .flat_map(|frame| {
+            for (y, x) in state.iter_mut().zip(frame) {
+                *y = (1. - a) * x + a * *y;
+            }
+            state.clone()
+        })
+        .collect()
+}
+
+impl MockBehavior {
+    /// `output_count` stems of interleaved `input` at `sample_rate`, each as
+    /// long as it.
+    pub(crate) fn infer(
+        &self,
+        input: &[f32],
+        nb_channels: usize,
+        sample_rate: usize,
+        output_count: usize,
+    ) -> Vec<Vec<f32>> {
+        match self {
+            MockBehavior::Identity => vec![input.to_vec(); output_count],
+            MockBehavior::Delay(delay) => {
+                thread::sleep(*delay);
+                vec![input.to_vec(); output_count]
+            }
+            MockBehavior::Gains(gains) => (0..output_count)
+                .map(|i| {
+                    let gain = gains.get(i).copied().unwrap_or(0.);
+                    input.iter().map(|x| x * gain).collect()
+                })
+                .collect(),
+            MockBehavior::Bands(bands) => {
+                // Stem `i` is the difference of the low-passes at its edges,
+                // the first edge is 0 Hz and the last one is the input itself.
+                let mut edges = vec![vec![0.; input.len()]];
+                for cutoff_hz in bands.iter().take(output_count.saturating_sub(1)) {
+                    edges.push(low_pass(input, nb_channels, sample_rate, *cutoff_hz));
+                }
+                while edges.len() < output_count {
+                    edges.push(edges.last().unwrap().clone());
+                }
+                edges.push(input.to_vec());
+                edges
+                    .windows(2)
+                    .map(|x| {
+                        x[1].iter()
+                            .zip(&x[0])
+                            .map(|(high, low)| high - low)
+                            .collect()
+                    })
+                    .collect()
+            }
+        }
+    }
+}
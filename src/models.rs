@@ -0,0 +1,371 @@
+//! Installed models: listing, inspection, verification and installation.
+//!
+//! Models live in `<models_dir>/<name>` as TensorFlow SavedModels. Installing
+//! an archive also installs `<models_dir>/SHA256SUMS` (`<hex>  <path>` per
+//! line, as `sha256sum` does), which `verify` checks the model files against.
+use anyhow::{anyhow, bail, Context, Result};
+use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read, Seek},
+    process,
+};
+#[cfg(feature = "tensorflow")]
+use tensorflow::{Graph, SavedModelBundle, SessionOptions, TensorInfo};
+use tracing::info;
+
+#[cfg(feature = "embed-models")]
+use crate::embedded;
+use crate::splitter::SpleeterModelInfo;
+use crate::utils;
+
+/// Checksum file in the models dir.
+pub const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+/// Sample rate every Spleeter model runs at.
+pub const MODEL_SAMPLE_RATE: usize = 44100;
+
+/// A known model and whether it is installed.
+#[derive(Debug, Serialize)]
+pub struct ModelStatus {
+    pub name: &'static str,
+    pub tracks: Vec<&'static str>,
+    pub installed: bool,
+    /// Included in the binary, used when not installed.
+    pub embedded: bool,
+}
+
+/// Input or output of a model signature.
+#[derive(Debug, Serialize)]
+pub struct TensorDescription {
+    pub key: String,
+    pub tensor: String,
+    pub dtype: String,
+    pub shape: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SignatureDescription {
+    pub name: String,
+    pub method_name: String,
+    pub inputs: Vec<TensorDescription>,
+    pub outputs: Vec<TensorDescription>,
+}
+
+/// What `models inspect` reports.
+#[derive(Debug, Serialize)]
+pub struct ModelDetails {
+    pub name: &'static str,
+    pub path: PathBuf,
+    pub tracks: Vec<&'static str>,
+    pub sample_rate: usize,
+    pub signatures: Vec<SignatureDescription>,
+    /// Operation type to count.
+    pub operations: BTreeMap<String, usize>,
+}
+
+/// A file whose checksum doesn't match `SHA256SUMS`.
+#[derive(Debug, Serialize)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub expected: String,
+    /// `None` if the file is missing.
+    pub actual: Option<String>,
+}
+
+pub fn list(models_dir: &Path) -> Vec<ModelStatus> {
+    SpleeterModelInfo::all()
+        .map(|model_info| ModelStatus {
+            name: model_info.name,
+            tracks: model_info.track_names.clone(),
+            installed: models_dir.join(model_info.name).is_dir(),
+            embedded: is_embedded(model_info.name),
+        })
+        .collect()
+}
+
+/// Whether the binary includes `model_name`, check `embedded`.
+#[cfg(feature = "embed-models")]
+pub fn is_embedded(model_name: &str) -> bool {
+    embedded::get(model_name).is_some()
+}
+
+#[cfg(not(feature = "embed-models"))]
+pub fn is_embedded(_model_name: &str) -> bool {
+    false
+}
+
+/// SavedModel directory of `model_info`, in `models_dir` when installed,
+/// otherwise the embedded one if any, unpacked on first use.
+pub fn model_path(model_info: &SpleeterModelInfo, models_dir: &Path) -> Result<PathBuf> {
+    let path = models_dir.join(model_info.name);
+    #[cfg(feature = "embed-models")]
+    if !path.is_dir() {
+        if let Some(model) = embedded::get(model_info.name) {
+            return Ok(model.unpack()?.join(model_info.name));
+        }
+    }
+    Ok(path)
+}
+
+#[cfg(not(feature = "tensorflow"))]
+pub fn inspect(
+    _model_info: &'static SpleeterModelInfo,
+    _models_dir: &Path,
+) -> Result<ModelDetails> {
+    bail!("Built without the `tensorflow` feature.")
+}
+
+#[cfg(feature = "tensorflow")]
+pub fn inspect(model_info: &'static SpleeterModelInfo, models_dir: &Path) -> Result<ModelDetails> {
+    let path = model_path(model_info, models_dir)?;
+    let mut graph = Graph::new();
+    let bundle = SavedModelBundle::load(&SessionOptions::new(), ["serve"], &mut graph, &path)
+        .context("Cannot load session")?;
+
+    let describe = |tensors: &std::collections::HashMap<String, TensorInfo>| {
+        let mut tensors: Vec<_> = tensors
+            .iter()
+            .map(|(key, tensor)| TensorDescription {
+                key: key.clone(),
+                tensor: format!("{}:{}", tensor.name().name, tensor.name().index),
+                dtype: format!("{}", tensor.dtype()),
+                shape: format!("{}", tensor.shape()),
+            })
+            .collect();
+        tensors.sort_by(|a, b| a.key.cmp(&b.key));
+        tensors
+    };
+    let mut signatures: Vec<_> = bundle
+        .meta_graph_def()
+        .signatures()
+        .iter()
+        .map(|(name, signature)| SignatureDescription {
+            name: name.clone(),
+            method_name: signature.method_name().to_owned(),
+            inputs: describe(signature.inputs()),
+            outputs: describe(signature.outputs()),
+        })
+        .collect();
+    signatures.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut operations = BTreeMap::new();
+    for operation in graph.operation_iter() {
+        let op_type = operation.op_type().context("Get operation type failed.")?;
+        *operations.entry(op_type).or_default() += 1;
+    }
+
+    Ok(ModelDetails {
+        name: model_info.name,
+        path,
+        tracks: model_info.track_names.clone(),
+        sample_rate: MODEL_SAMPLE_RATE,
+        signatures,
+        operations,
+    })
+}
+
+/// Parse `SHA256SUMS` into (relative path, hex digest).
+fn read_checksums(models_dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let checksums_path = models_dir.join(CHECKSUMS_FILE);
+    let checksums = fs::read_to_string(&checksums_path)
+        .with_context(|| anyhow!("Read {} failed.", checksums_path))?;
+    checksums
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (digest, path) = line
+                .split_once(' ')
+                .with_context(|| anyhow!("Invalid checksum line: {}", line))?;
+            // `sha256sum` marks binary mode with `*`.
+            let path = path.trim_start_matches([' ', '*']);
+            Ok((PathBuf::from(path), digest.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+/// Check files of `names` (every model when empty) against `SHA256SUMS`.
+pub fn verify(models_dir: &Path, names: &[&str]) -> Result<Vec<Mismatch>> {
+    let mut mismatches = Vec::new();
+    let mut checked = 0;
+    for (path, expected) in read_checksums(models_dir)? {
+        let model_name = path.components().next().map(|x| x.as_str());
+        if !names.is_empty() && !model_name.map_or(false, |x| names.contains(&x)) {
+            continue;
+        }
+        checked += 1;
+        let full_path = models_dir.join(&path);
+        let actual = if full_path.is_file() {
+            Some(utils::sha256_file(&full_path)?)
+        } else {
+            None
+        };
+        if actual.as_deref() != Some(expected.as_str()) {
+            mismatches.push(Mismatch {
+                path,
+                expected,
+                actual,
+            });
+        }
+    }
+    if checked == 0 {
+        bail!("No checksums found for: {}", names.join(", "));
+    }
+    Ok(mismatches)
+}
+
+/// Unpack a release archive (`models.zip`) into `models_dir`, returns the
+/// installed model names.
+///
+/// The archive's leading `models/` directory is stripped. Unpacked files are
+/// checked against the archive's `SHA256SUMS`. Archives without one are only
+/// installed when `sha256`, the expected digest of the whole archive, is
+/// given and matches; `SHA256SUMS` is then written from the unpacked files.
+/// Files are unpacked and checked in a temporary dir inside `models_dir`
+/// first, a bad archive leaves the installed models untouched.
+pub fn install(
+    archive_path: &Path,
+    models_dir: &Path,
+    sha256: Option<&str>,
+) -> Result<Vec<String>> {
+    if let Some(expected) = sha256 {
+        let actual = utils::sha256_file(archive_path)?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            bail!(
+                "Archive checksum mismatch, expected {}, got {}",
+                expected,
+                actual
+            );
+        }
+    }
+    let archive = File::open(archive_path).context("Open model archive failed.")?;
+    let archive = zip::ZipArchive::new(archive).context("Read model archive failed.")?;
+    let has_checksums = archive
+        .file_names()
+        .any(|name| name.trim_start_matches("models/") == CHECKSUMS_FILE);
+    if !has_checksums && sha256.is_none() {
+        bail!(
+            "{} has no {}, pass the archive's SHA-256 to check it against.",
+            archive_path,
+            CHECKSUMS_FILE
+        );
+    }
+
+    let staging_dir = models_dir.join(format!(".install.{}", process::id()));
+    let _ = fs::remove_dir_all(&staging_dir);
+    let result =
+        unpack_checked(archive.into_inner(), &staging_dir, has_checksums).and_then(|files| {
+            move_into(&staging_dir, models_dir)?;
+            Ok(files)
+        });
+    let _ = fs::remove_dir_all(&staging_dir);
+    let files = result?;
+    for name in &files {
+        info!("Installed: {}", models_dir.join(name));
+    }
+
+    let mut names: Vec<String> = files
+        .iter()
+        .filter_map(|name| name.components().next())
+        .map(|x| x.as_str().to_owned())
+        .filter(|name| SpleeterModelInfo::get_by_name(name).is_some())
+        .collect();
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Unpack `archive` into `dir` and check it against its `SHA256SUMS`, or
+/// write one, returns the unpacked model files.
+fn unpack_checked(
+    archive: impl Read + Seek,
+    dir: &Path,
+    has_checksums: bool,
+) -> Result<Vec<PathBuf>> {
+    let mut files = unpack(archive, dir)?;
+    files.retain(|name| name.as_str() != CHECKSUMS_FILE);
+    if !files
+        .iter()
+        .filter_map(|name| name.components().next())
+        .any(|name| SpleeterModelInfo::get_by_name(name.as_str()).is_some())
+    {
+        bail!("No known model found in the archive.");
+    }
+
+    if has_checksums {
+        let mismatches = verify(dir, &[])?;
+        if let Some(mismatch) = mismatches.first() {
+            bail!("Checksum mismatch: {}", mismatch.path);
+        }
+    } else {
+        files.sort();
+        let checksums = files
+            .iter()
+            .map(|name| {
+                Ok(format!(
+                    "{}  {}\n",
+                    utils::sha256_file(&dir.join(name))?,
+                    name
+                ))
+            })
+            .collect::<Result<String>>()?;
+        fs::write(dir.join(CHECKSUMS_FILE), checksums).context("Write checksums failed.")?;
+    }
+    Ok(files)
+}
+
+/// Move every entry of `from` into `to`, replacing existing ones.
+fn move_into(from: &Path, to: &Path) -> Result<()> {
+    for entry in fs::read_dir(from).with_context(|| anyhow!("Read {} failed.", from))? {
+        let entry = entry.with_context(|| anyhow!("Read {} failed.", from))?;
+        let file_name = entry.file_name();
+        let file_name = file_name
+            .to_str()
+            .with_context(|| anyhow!("Non UTF-8 file name in {}", from))?;
+        let path = to.join(file_name);
+        if path.is_dir() {
+            fs::remove_dir_all(&path).with_context(|| anyhow!("Remove {} failed.", path))?;
+        } else if path.exists() {
+            fs::remove_file(&path).with_context(|| anyhow!("Remove {} failed.", path))?;
+        }
+        fs::rename(from.join(file_name), &path)
+            .with_context(|| anyhow!("Move {} failed.", path))?;
+    }
+    Ok(())
+}
+
+/// Unpack a zip archive into `models_dir`, returns the unpacked files
+/// relative to it. A leading `models/` directory is stripped.
+pub(crate) fn unpack(archive: impl Read + Seek, models_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut archive = zip::ZipArchive::new(archive).context("Read model archive failed.")?;
+    fs::create_dir_all(models_dir).context("Create models dir failed.")?;
+
+    let mut files = Vec::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).context("Read archive entry failed.")?;
+        // `enclosed_name` rejects absolute paths and `..`.
+        let name = entry
+            .enclosed_name()
+            .and_then(|x| x.to_str())
+            .map(PathBuf::from)
+            .with_context(|| anyhow!("Invalid archive entry: {}", entry.name()))?;
+        let name = name.strip_prefix("models").unwrap_or(&name).to_owned();
+        if name.as_str().is_empty() {
+            continue;
+        }
+        let path = models_dir.join(&name);
+        if entry.is_dir() {
+            fs::create_dir_all(&path).with_context(|| anyhow!("Create {} failed.", path))?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| anyhow!("Create {} failed.", parent))?;
+        }
+        let mut file = File::create(&path).with_context(|| anyhow!("Create {} failed.", path))?;
+        io::copy(&mut entry, &mut file).with_context(|| anyhow!("Unpack {} failed.", path))?;
+        files.push(name);
+    }
+    Ok(files)
+}
@@ -0,0 +1,466 @@
+//! Native Instruments Stems (`.stem.mp4`) support.
+//!
+//! A stem file is an MP4 holding the original mix followed by exactly four
+//! stem streams, plus a `moov/udta/stem` atom describing the stems in JSON.
+//! Check https://www.native-instruments.com/en/specials/stems/ for details.
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path as Path;
+use rsmpeg::ffi;
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+/// Codec of the mix and stem streams.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum NiStemsCodec {
+    Aac,
+    Alac,
+}
+
+impl NiStemsCodec {
+    pub fn codec_id(self) -> ffi::AVCodecID {
+        match self {
+            NiStemsCodec::Aac => ffi::AV_CODEC_ID_AAC,
+            NiStemsCodec::Alac => ffi::AV_CODEC_ID_ALAC,
+        }
+    }
+}
+
+/// The four stem slots in NI order.
+const SLOTS: [NiSlot; 4] = [
+    NiSlot {
+        name: "Drums",
+        color: "#009E73",
+    },
+    NiSlot {
+        name: "Bass",
+        color: "#D55E00",
+    },
+    NiSlot {
+        name: "Other",
+        color: "#CC79A7",
+    },
+    NiSlot {
+        name: "Vocals",
+        color: "#56B4E9",
+    },
+];
+
+struct NiSlot {
+    name: &'static str,
+    color: &'static str,
+}
+
+/// One of the four stems written into the stem file.
+pub struct NiStem {
+    pub name: String,
+    pub color: &'static str,
+    /// Interleaved samples, silence when the model has no matching track.
+    pub samples: Vec<f32>,
+}
+
+/// Mastering limiter settings stored in the `stem` atom.
+pub struct NiLimiter {
+    pub enabled: bool,
+    /// Ceiling in dB.
+    pub ceiling: f32,
+    /// Release in seconds.
+    pub release: f32,
+}
+
+impl Default for NiLimiter {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ceiling: -0.35,
+            release: 0.05,
+        }
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Map model tracks to the four NI slots.
+///
+/// `drums`, `bass` and `vocals` go to their own slots, every other track
+/// (`accompaniment`, `piano`, `other`) is mixed into the `Other` slot. Slots
+/// without a matching track are silent.
+pub fn map_to_slots(track_names: &[&str], tracks: &[Vec<f32>], length: usize) -> Vec<NiStem> {
+    SLOTS
+        .iter()
+        .map(|slot| {
+            let matches = |track_name: &str| match slot.name {
+                "Other" => !["drums", "bass", "vocals"].contains(&track_name),
+                name => name.eq_ignore_ascii_case(track_name),
+            };
+            let mut samples = vec![0.; length];
+            let mut names = Vec::new();
+            for (track_name, track) in track_names.iter().zip(tracks) {
+                if matches(track_name) {
+                    names.push(capitalize(track_name));
+                    for (sample, x) in samples.iter_mut().zip(track) {
+                        *sample += x;
+                    }
+                }
+            }
+            let name = if names.is_empty() {
+                slot.name.to_owned()
+            } else {
+                names.join(" & ")
+            };
+            NiStem {
+                name,
+                color: slot.color,
+                samples,
+            }
+        })
+        .collect()
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// JSON payload of the `stem` atom.
+pub fn stem_metadata(stems: &[NiStem], limiter: &NiLimiter) -> String {
+    let stems = stems
+        .iter()
+        .map(|stem| {
+            format!(
+                r#"{{"color":"{}","name":"{}"}}"#,
+                stem.color,
+                escape_json(&stem.name)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        concat!(
+            r#"{{"version":1,"mastering_dsp":{{"#,
+            r#""compressor":{{"enabled":false,"ratio":3,"output_gain":0.5,"release":0.3,"#,
+            r#""attack":0.003,"input_gain":0.5,"threshold":0,"hp_cutoff":300,"dry_wet":50}},"#,
+            r#""limiter":{{"enabled":{},"release":{},"threshold":0,"ceiling":{}}}}},"#,
+            r#""stems":[{}]}}"#
+        ),
+        limiter.enabled, limiter.release, limiter.ceiling, stems
+    )
+}
+
+/// Returns (size, type, header size) of a box starting with `header`, with
+/// `available` bytes left in its parent. A size of 0 extends to the end.
+fn parse_box_header(header: &[u8], available: u64) -> Result<(u64, [u8; 4], u64)> {
+    if header.len() < 8 {
+        bail!("Truncated box header.");
+    }
+    let size = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+    let box_type = header[4..8].try_into().unwrap();
+    let (size, header_size) = match size {
+        0 => (available, 8),
+        1 => {
+            if header.len() < 16 {
+                bail!("Truncated box header.");
+            }
+            let size = u64::from_be_bytes(header[8..16].try_into().unwrap());
+            (size, 16)
+        }
+        size => (size, 8),
+    };
+    if size < header_size || size > available {
+        bail!("Invalid box size.");
+    }
+    Ok((size, box_type, header_size))
+}
+
+/// Returns (size, type, header size) of the box at the start of `data`.
+fn read_box_header(data: &[u8]) -> Result<(usize, [u8; 4], usize)> {
+    let (size, box_type, header_size) = parse_box_header(data, data.len() as u64)?;
+    Ok((size as usize, box_type, header_size as usize))
+}
+
+/// Find a direct child box, returns its range in `data`.
+fn find_box(data: &[u8], box_type: &[u8; 4]) -> Result<Option<(usize, usize)>> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let (size, current_type, _) = read_box_header(&data[offset..])?;
+        if &current_type == box_type {
+            return Ok(Some((offset, offset + size)));
+        }
+        offset += size;
+    }
+    Ok(None)
+}
+
+/// Returns (offset, size) of the first top level `box_type` box of `file`,
+/// reading only box headers.
+fn find_file_box(file: &mut File, box_type: &[u8; 4]) -> Result<Option<(u64, u64)>> {
+    let len = file.metadata()?.len();
+    let mut offset = 0;
+    while offset < len {
+        let mut header = Vec::with_capacity(16);
+        file.seek(SeekFrom::Start(offset))?;
+        (&mut *file).take(16).read_to_end(&mut header)?;
+        let (size, current_type, _) = parse_box_header(&header, len - offset)?;
+        if &current_type == box_type {
+            return Ok(Some((offset, size)));
+        }
+        offset += size;
+    }
+    Ok(None)
+}
+
+fn make_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8 + payload.len());
+    data.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+    data.extend_from_slice(box_type);
+    data.extend_from_slice(payload);
+    data
+}
+
+/// Add `stem_box` to `udta` of the children of `moov`, creating `udta` if
+/// missing.
+fn add_to_udta(moov_payload: &[u8], stem_box: &[u8]) -> Result<Vec<u8>> {
+    Ok(match find_box(moov_payload, b"udta")? {
+        Some((udta_begin, udta_end)) => {
+            let (_, _, udta_header_size) = read_box_header(&moov_payload[udta_begin..])?;
+            let mut udta_payload = moov_payload[udta_begin + udta_header_size..udta_end].to_vec();
+            udta_payload.extend_from_slice(stem_box);
+            [
+                &moov_payload[..udta_begin],
+                &make_box(b"udta", &udta_payload),
+                &moov_payload[udta_end..],
+            ]
+            .concat()
+        }
+        None => [moov_payload, &make_box(b"udta", stem_box)].concat(),
+    })
+}
+
+/// Shift the chunk offsets at or after `threshold` by `delta`, in every
+/// `stco` and `co64` box under `data`.
+fn patch_chunk_offsets(data: &mut [u8], threshold: u64, delta: i64) -> Result<()> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let (size, box_type, header_size) = read_box_header(&data[offset..])?;
+        let payload = &mut data[offset + header_size..offset + size];
+        match &box_type {
+            b"trak" | b"mdia" | b"minf" | b"stbl" => {
+                patch_chunk_offsets(payload, threshold, delta)?
+            }
+            b"stco" => patch_offset_table(payload, 4, threshold, delta)?,
+            b"co64" => patch_offset_table(payload, 8, threshold, delta)?,
+            _ => {}
+        }
+        offset += size;
+    }
+    Ok(())
+}
+
+fn patch_offset_table(
+    payload: &mut [u8],
+    entry_size: usize,
+    threshold: u64,
+    delta: i64,
+) -> Result<()> {
+    // Version and flags, then the entry count.
+    if payload.len() < 8 {
+        bail!("Truncated chunk offset box.");
+    }
+    let count = u32::from_be_bytes(payload[4..8].try_into().unwrap()) as usize;
+    let entries = payload[8..].chunks_exact_mut(entry_size);
+    if entries.len() < count {
+        bail!("Truncated chunk offset box.");
+    }
+    for entry in entries.take(count) {
+        let chunk_offset = match entry_size {
+            4 => u32::from_be_bytes(entry.try_into().unwrap()) as u64,
+            _ => u64::from_be_bytes(entry.try_into().unwrap()),
+        };
+        if chunk_offset < threshold {
+            continue;
+        }
+        let chunk_offset = chunk_offset
+            .checked_add_signed(delta)
+            .context("Invalid chunk offset.")?;
+        match entry_size {
+            4 => {
+                let chunk_offset =
+                    u32::try_from(chunk_offset).context("Chunk offset overflows stco.")?;
+                entry.copy_from_slice(&chunk_offset.to_be_bytes());
+            }
+            _ => entry.copy_from_slice(&chunk_offset.to_be_bytes()),
+        }
+    }
+    Ok(())
+}
+
+/// Add a `stem` atom holding `metadata` to `moov/udta` of an MP4 file.
+///
+/// The file is rewritten through a temporary file next to it, only `moov`
+/// is held in memory. When `moov` comes before the media data, the chunk
+/// offsets are moved by the growth of `moov`.
+pub fn insert_stem_atom(path: &Path, metadata: &str) -> Result<()> {
+    let mut file = File::open(path).context("Open stem file failed.")?;
+    let (moov_begin, moov_size) =
+        find_file_box(&mut file, b"moov")?.context("No moov box found.")?;
+    let moov_end = moov_begin + moov_size;
+    let mut moov = vec![0; moov_size as usize];
+    file.seek(SeekFrom::Start(moov_begin))?;
+    file.read_exact(&mut moov)
+        .context("Read moov box failed.")?;
+    let (_, _, moov_header_size) = read_box_header(&moov)?;
+    if moov_header_size != 8 {
+        bail!("64-bit moov box is not supported.");
+    }
+
+    let stem_box = make_box(b"stem", metadata.as_bytes());
+    let mut moov_payload = add_to_udta(&moov[8..], &stem_box)?;
+    let delta = (moov_payload.len() + 8) as i64 - moov_size as i64;
+    // Only data after `moov` moves.
+    patch_chunk_offsets(&mut moov_payload, moov_end, delta)?;
+    let moov = make_box(b"moov", &moov_payload);
+
+    let temp_path = path.with_file_name(format!("{}.stem", path.file_name().unwrap_or("")));
+    let result = (|| -> Result<()> {
+        let mut output = BufWriter::new(File::create(&temp_path)?);
+        file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut (&mut file).take(moov_begin), &mut output)?;
+        output.write_all(&moov)?;
+        file.seek(SeekFrom::Start(moov_end))?;
+        io::copy(&mut file, &mut output)?;
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok(())
+    })();
+    if let Err(e) = result.and_then(|()| Ok(fs::rename(&temp_path, path)?)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e).context("Write stem file failed.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use camino::Utf8PathBuf as PathBuf;
+
+    const MEDIA: &[u8] = b"0123456789abcdef";
+
+    fn stbl_with(offsets: &[u32]) -> Vec<u8> {
+        let mut stco = vec![0, 0, 0, 0];
+        stco.extend((offsets.len() as u32).to_be_bytes());
+        offsets.iter().for_each(|x| stco.extend(x.to_be_bytes()));
+        let stbl = make_box(b"stbl", &make_box(b"stco", &stco));
+        let mdia = make_box(b"mdia", &make_box(b"minf", &stbl));
+        make_box(b"trak", &mdia)
+    }
+
+    fn chunk_offsets(data: &[u8]) -> Vec<u32> {
+        let mut payload = data;
+        for box_type in [b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stco"] {
+            let (begin, end) = find_box(payload, box_type).unwrap().unwrap();
+            let (_, _, header_size) = read_box_header(&payload[begin..]).unwrap();
+            payload = &payload[begin + header_size..end];
+        }
+        payload[8..]
+            .chunks(4)
+            .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+            .collect()
+    }
+
+    fn stem_metadata(data: &[u8]) -> Vec<Vec<u8>> {
+        let (begin, end) = find_box(data, b"moov").unwrap().unwrap();
+        let moov = &data[begin + 8..end];
+        let (begin, end) = find_box(moov, b"udta").unwrap().unwrap();
+        let mut udta = &moov[begin + 8..end];
+        let mut stems = Vec::new();
+        while let Some((begin, end)) = find_box(udta, b"stem").unwrap() {
+            stems.push(udta[begin + 8..end].to_vec());
+            udta = &udta[end..];
+        }
+        stems
+    }
+
+    fn insert(name: &str, data: &[u8], metadata: &str) -> Vec<u8> {
+        let path = PathBuf::from_path_buf(std::env::temp_dir())
+            .unwrap()
+            .join(format!(
+                "rspleeter-ni-stems-{}-{}.mp4",
+                std::process::id(),
+                name
+            ));
+        fs::write(&path, data).unwrap();
+        insert_stem_atom(&path, metadata).unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        data
+    }
+
+    #[test]
+    fn moov_first_moves_chunk_offsets() {
+        let ftyp = make_box(b"ftyp", b"isom\0\0\0\0");
+        // Chunk offsets depend on the moov size, which doesn't depend on them.
+        let moov_len = make_box(b"moov", &stbl_with(&[0, 0])).len();
+        let media_begin = (ftyp.len() + moov_len + 8) as u32;
+        let moov = make_box(b"moov", &stbl_with(&[media_begin, media_begin + 8]));
+        let data = [ftyp, moov, make_box(b"mdat", MEDIA)].concat();
+
+        let data = insert("moov-first", &data, "{}");
+        let (mdat_begin, mdat_end) = find_box(&data, b"mdat").unwrap().unwrap();
+        assert_eq!(&data[mdat_begin + 8..mdat_end], MEDIA);
+        let media_begin = (mdat_begin + 8) as u32;
+        assert_eq!(chunk_offsets(&data), [media_begin, media_begin + 8]);
+        assert_eq!(stem_metadata(&data), [b"{}".to_vec()]);
+    }
+
+    #[test]
+    fn moov_last_keeps_chunk_offsets_and_udta() {
+        let ftyp = make_box(b"ftyp", b"isom\0\0\0\0");
+        let media_begin = (ftyp.len() + 8) as u32;
+        let udta = make_box(b"udta", &make_box(b"name", b"mix"));
+        let moov = make_box(b"moov", &[stbl_with(&[media_begin]), udta].concat());
+        let data = [ftyp, make_box(b"mdat", MEDIA), moov].concat();
+
+        let data = insert("moov-last", &data, r#"{"stems":[]}"#);
+        assert_eq!(chunk_offsets(&data), [media_begin]);
+        let (begin, end) = find_box(&data, b"moov").unwrap().unwrap();
+        let moov = &data[begin + 8..end];
+        let (begin, end) = find_box(moov, b"udta").unwrap().unwrap();
+        assert!(find_box(&moov[begin + 8..end], b"name").unwrap().is_some());
+        assert_eq!(stem_metadata(&data), [br#"{"stems":[]}"#.to_vec()]);
+    }
+
+    #[test]
+    fn invalid_box_size_fails() {
+        let mut data = make_box(b"ftyp", b"isom\0\0\0\0");
+        data.extend(&100u32.to_be_bytes());
+        data.extend(b"moov");
+        assert!(read_box_header(&data[16..]).is_err());
+        let path = PathBuf::from_path_buf(std::env::temp_dir())
+            .unwrap()
+            .join(format!(
+                "rspleeter-ni-stems-{}-invalid.mp4",
+                std::process::id()
+            ));
+        fs::write(&path, &data).unwrap();
+        assert!(insert_stem_atom(&path, "{}").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+//! Atomic output files.
+//!
+//! Outputs are written to a temporary file next to the destination and
+//! renamed into place once complete, so an interrupted run never leaves a
+//! truncated file that looks valid.
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
+use once_cell::sync::Lazy;
+use std::{collections::HashSet, fs, process, sync::Mutex};
+use tracing::{info, warn};
+
+use crate::control::CancellationToken;
+use crate::error::Error;
+use crate::events::{self, Event};
+
+/// What to do when an output file already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Keep the existing file and don't write this output.
+    SkipExisting,
+    /// Abort with an error.
+    FailIfExists,
+}
+
+/// Temporary files currently being written, removed on interruption.
+static PARTIAL_OUTPUTS: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(Default::default);
+
+/// Cancel `cancellation_token` on Ctrl-C, letting running operations clean up
+/// after themselves. A second Ctrl-C removes partial outputs and exits.
+pub fn install_interrupt_handler(cancellation_token: CancellationToken) -> Result<()> {
+    ctrlc::set_handler(move || {
+        if !cancellation_token.is_cancelled() {
+            warn!("Interrupted, cancelling. Press Ctrl-C again to exit immediately.");
+            cancellation_token.cancel();
+            return;
+        }
+        for path in PARTIAL_OUTPUTS.lock().unwrap().drain() {
+            // Best effort, the process is exiting anyway.
+            let _ = fs::remove_file(&path);
+        }
+        warn!("Interrupted, partial outputs removed.");
+        // 128 + SIGINT
+        process::exit(130);
+    })
+    .context("Set Ctrl-C handler failed.")
+}
+
+/// Check existing outputs against the policy before doing any work.
+///
+/// Returns false when every output exists and is to be skipped.
+pub fn check_existing(paths: &[PathBuf], policy: OverwritePolicy) -> Result<bool> {
+    let existing: Vec<_> = paths.iter().filter(|path| path.exists()).collect();
+    match policy {
+        OverwritePolicy::FailIfExists if !existing.is_empty() => {
+            bail!("Output already exists: {}", existing[0]);
+        }
+        OverwritePolicy::SkipExisting if existing.len() == paths.len() => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+/// Temporary path of an output, keeping the extension so that the container
+/// can still be guessed from it.
+fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default();
+    let temp_name = match path.extension() {
+        Some(extension) => format!(
+            ".{}.{}.partial.{}",
+            path.file_stem().unwrap_or_default(),
+            process::id(),
+            extension
+        ),
+        None => format!(".{}.{}.partial", file_name, process::id()),
+    };
+    path.with_file_name(temp_name)
+}
+
+/// Write `path` through `write`, which receives a temporary path to write to.
+///
+/// The temporary file is renamed to `path` only if `write` succeeds, and
+/// removed otherwise. Returns false if the output was skipped.
+pub fn write_atomically(
+    path: &Path,
+    policy: OverwritePolicy,
+    write: impl FnOnce(&Path) -> Result<()>,
+) -> Result<bool> {
+    if path.exists() {
+        match policy {
+            OverwritePolicy::Overwrite => {
+                warn!("Overwriting: {}", path);
+                events::emit(Event::Warning {
+                    input: None,
+                    message: format!("Overwriting: {}", path),
+                });
+            }
+            OverwritePolicy::SkipExisting => {
+                info!("Skipping existing: {}", path);
+                return Ok(false);
+            }
+            OverwritePolicy::FailIfExists => bail!("Output already exists: {}", path),
+        }
+    }
+
+    info!("Writing: {}", path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(Error::Io)
+            .context("Create output dir failed.")?;
+    }
+    let temp_path = temp_path(path);
+    PARTIAL_OUTPUTS.lock().unwrap().insert(temp_path.clone());
+    let result = write(&temp_path).and_then(|_| {
+        fs::rename(&temp_path, path)
+            .map_err(Error::Io)
+            .with_context(|| format!("Rename {} to {} failed.", temp_path, path))
+    });
+    PARTIAL_OUTPUTS.lock().unwrap().remove(&temp_path);
+    if result.is_err() && temp_path.exists() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.map(|_| true)
+}
@@ -0,0 +1,122 @@
+//! Inspect inputs without splitting them.
+use anyhow::{Context, Result};
+use camino::Utf8Path as Path;
+use rsmpeg::{avformat::AVFormatContextInput, avutil::av_q2d, ffi};
+use serde::{Serialize, Serializer};
+use std::ffi::{CStr, CString};
+
+use crate::utils::{self, AudioParameters};
+
+/// Container and streams of an input.
+#[derive(Debug, Serialize)]
+pub struct ProbeInfo {
+    pub format_name: String,
+    pub format_long_name: String,
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    pub bit_rate: Option<i64>,
+    #[serde(serialize_with = "serialize_tags")]
+    pub tags: Vec<(String, String)>,
+    /// Index of the audio stream a split would decode.
+    pub best_audio_stream: Option<usize>,
+    pub streams: Vec<StreamInfo>,
+}
+
+/// One stream of an input.
+#[derive(Debug, Serialize)]
+pub struct StreamInfo {
+    pub index: usize,
+    pub codec_type: String,
+    pub codec_name: String,
+    pub sample_rate: Option<i32>,
+    pub channels: Option<i32>,
+    pub channel_layout: Option<String>,
+    pub sample_format: Option<String>,
+    pub bit_rate: Option<i64>,
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    #[serde(serialize_with = "serialize_tags")]
+    pub tags: Vec<(String, String)>,
+}
+
+/// Serialize as an object, keeping FFmpeg's order.
+fn serialize_tags<S: Serializer>(
+    tags: &[(String, String)],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_map(tags.iter().map(|(key, value)| (key, value)))
+}
+
+fn c_str_to_string(s: *const std::os::raw::c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned()
+}
+
+fn media_type_name(media_type: ffi::AVMediaType) -> String {
+    let name = unsafe { ffi::av_get_media_type_string(media_type) };
+    if name.is_null() {
+        "unknown".to_owned()
+    } else {
+        c_str_to_string(name)
+    }
+}
+
+/// Probe `path`, also returning the parameters of the audio stream a split
+/// would decode.
+pub fn probe(path: &Path) -> Result<(ProbeInfo, Option<AudioParameters>)> {
+    // unwrap: &str ensures no internal null bytes.
+    let path = CString::new(path.as_str()).unwrap();
+    let input_format_context =
+        AVFormatContextInput::open(&path).context("Open audio file failed.")?;
+
+    let best_audio_stream = input_format_context
+        .find_best_stream(ffi::AVMEDIA_TYPE_AUDIO)
+        .context("Find best stream failed.")?
+        .map(|(stream_index, _)| stream_index);
+
+    let streams = input_format_context
+        .streams()
+        .iter()
+        .enumerate()
+        .map(|(index, stream)| {
+            let codecpar = stream.codecpar();
+            let is_audio = codecpar.codec_type == ffi::AVMEDIA_TYPE_AUDIO;
+            StreamInfo {
+                index,
+                codec_type: media_type_name(codecpar.codec_type),
+                codec_name: utils::codec_name(codecpar.codec_id),
+                sample_rate: is_audio.then_some(codecpar.sample_rate),
+                channels: is_audio.then_some(codecpar.ch_layout.nb_channels),
+                channel_layout: is_audio.then(|| utils::describe_ch_layout(&codecpar.ch_layout)),
+                sample_format: is_audio.then(|| utils::sample_fmt_name(codecpar.format)),
+                bit_rate: (codecpar.bit_rate > 0).then_some(codecpar.bit_rate),
+                duration: (stream.duration != ffi::AV_NOPTS_VALUE)
+                    .then(|| stream.duration as f64 * av_q2d(stream.time_base)),
+                tags: utils::dict_entries(stream.metadata),
+            }
+        })
+        .collect();
+
+    let audio_parameters = best_audio_stream.map(|stream_index| {
+        let stream = input_format_context.streams().get(stream_index).unwrap();
+        AudioParameters {
+            time_base: stream.time_base,
+            codecpar: stream.codecpar().clone(),
+        }
+    });
+
+    let input_format = unsafe { &*input_format_context.iformat };
+    let info = ProbeInfo {
+        format_name: c_str_to_string(input_format.name),
+        format_long_name: c_str_to_string(input_format.long_name),
+        duration: (input_format_context.duration != ffi::AV_NOPTS_VALUE)
+            .then(|| input_format_context.duration as f64 / ffi::AV_TIME_BASE as f64),
+        bit_rate: (input_format_context.bit_rate > 0).then_some(input_format_context.bit_rate),
+        tags: utils::dict_entries(input_format_context.metadata),
+        best_audio_stream,
+        streams,
+    };
+    Ok((info, audio_parameters))
+}
@@ -0,0 +1,298 @@
+//! Post-processing of separated stems before they are encoded.
+//!
+//! Chains are registered per stem with [`StemProcessors::add`] or read from
+//! the `[processors]` table of the config file, both can be combined:
+//!
+//! ```toml
+//! [[processors.vocals]]
+//! type = "gate"
+//! threshold_db = -45
+//!
+//! [[processors.bass]]
+//! type = "high_pass"
+//! cutoff_hz = 40
+//! ```
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, f32::consts::PI};
+
+/// What a processor may need besides the stem.
+pub struct StemContext<'a> {
+    pub track_name: &'a str,
+    pub sample_rate: usize,
+    pub nb_channels: usize,
+    /// The interleaved mixture the stem was separated from.
+    pub mixture: &'a [f32],
+}
+
+/// Transforms a separated stem in place.
+///
+/// A processor sees a whole stem per call, state such as filter memory
+/// lives within the call.
+pub trait StemProcessor: Send + Sync {
+    /// `samples` are interleaved as described by `context`.
+    fn process(&self, samples: &mut [f32], context: &StemContext) -> Result<()>;
+}
+
+impl<F> StemProcessor for F
+where
+    F: Fn(&mut [f32], &StemContext) -> Result<()> + Send + Sync,
+{
+    fn process(&self, samples: &mut [f32], context: &StemContext) -> Result<()> {
+        self(samples, context)
+    }
+}
+
+/// Processor chains keyed by stem name, run in registration order.
+#[derive(Default)]
+pub struct StemProcessors {
+    chains: BTreeMap<String, Vec<Box<dyn StemProcessor>>>,
+}
+
+impl StemProcessors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Built-in processors described by the config file.
+    pub fn from_config(config: &BTreeMap<String, Vec<ProcessorConfig>>) -> Result<Self> {
+        let mut processors = Self::new();
+        for (track_name, chain) in config {
+            for processor in chain {
+                processor.validate()?;
+                processors.add(track_name, processor.clone());
+            }
+        }
+        Ok(processors)
+    }
+
+    /// Append `processor` to the chain of `track_name`.
+    pub fn add(&mut self, track_name: &str, processor: impl StemProcessor + 'static) -> &mut Self {
+        self.chains
+            .entry(track_name.to_owned())
+            .or_default()
+            .push(Box::new(processor));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chains.is_empty()
+    }
+
+    /// Run the chain of `context.track_name`, if any.
+    pub fn process(&self, samples: &mut [f32], context: &StemContext) -> Result<()> {
+        if let Some(chain) = self.chains.get(context.track_name) {
+            for processor in chain {
+                processor.process(samples, context)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Built-in processors, as written in the config file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ProcessorConfig {
+    Gain {
+        db: f32,
+    },
+    /// Silences the stem while its level stays under `threshold_db`.
+    Gate {
+        threshold_db: f32,
+        #[serde(default = "default_release_ms")]
+        release_ms: f32,
+    },
+    /// 12 dB/octave Butterworth filters.
+    HighPass {
+        cutoff_hz: f32,
+    },
+    LowPass {
+        cutoff_hz: f32,
+    },
+}
+
+fn default_release_ms() -> f32 {
+    100.
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+impl ProcessorConfig {
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            ProcessorConfig::Gate { release_ms, .. } if release_ms < 0. => {
+                bail!("Gate release must not be negative.")
+            }
+            ProcessorConfig::HighPass { cutoff_hz } | ProcessorConfig::LowPass { cutoff_hz }
+                if cutoff_hz <= 0. =>
+            {
+                bail!("Filter cutoff must be positive.")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl StemProcessor for ProcessorConfig {
+    fn process(&self, samples: &mut [f32], context: &StemContext) -> Result<()> {
+        match *self {
+            ProcessorConfig::Gain { db } => {
+                let gain = db_to_gain(db);
+                samples.iter_mut().for_each(|x| *x *= gain);
+            }
+            ProcessorConfig::Gate {
+                threshold_db,
+                release_ms,
+            } => gate(samples, context, db_to_gain(threshold_db), release_ms),
+            ProcessorConfig::HighPass { cutoff_hz } => {
+                Biquad::new(context.sample_rate, cutoff_hz, true).run(samples, context.nb_channels)
+            }
+            ProcessorConfig::LowPass { cutoff_hz } => {
+                Biquad::new(context.sample_rate, cutoff_hz, false).run(samples, context.nb_channels)
+            }
+        }
+        Ok(())
+    }
+}
+
+fn gate(samples: &mut [f32], context: &StemContext, threshold: f32, release_ms: f32) {
+    let sample_rate = context.sample_rate as f32;
+    // Per frame coefficients of a peak follower and a 1 ms gain ramp.
+    let release = (-1. / (release_ms / 1000. * sample_rate).max(1.)).exp();
+    let ramp = (-1. / (sample_rate / 1000.)).exp();
+    let (mut envelope, mut gain) = (0f32, 0f32);
+    for frame in samples.chunks_mut(context.nb_channels.max(1)) {
+        let peak = frame.iter().fold(0f32, |peak, x| peak.max(x.abs()));
+        envelope = peak.max(envelope * release);
+        let target = if envelope >= threshold { 1. } else { 0. };
+        gain = target + (gain - target) * ramp;
+        frame.iter_mut().for_each(|x| *x *= gain);
+    }
+}
+
+/// RBJ cookbook filter with Q = 1/sqrt(2).
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+}
+
+impl Biquad {
+    fn new(sample_rate: usize, cutoff_hz: f32, high_pass: bool) -> Self {
+        // Clamp below Nyquist to stay stable.
+        let cutoff_hz = cutoff_hz.min(sample_rate as f32 * 0.49);
+        let w0 = 2. * PI * cutoff_hz / sample_rate as f32;
+        let alpha = w0.sin() / 2f32.sqrt();
+        let cos = w0.cos();
+        let a0 = 1. + alpha;
+        let b = if high_pass {
+            [(1. + cos) / 2., -(1. + cos), (1. + cos) / 2.]
+        } else {
+            [(1. - cos) / 2., 1. - cos, (1. - cos) / 2.]
+        };
+        Self {
+            b: b.map(|x| x / a0),
+            a: [-2. * cos / a0, (1. - alpha) / a0],
+        }
+    }
+
+    fn run(&self, samples: &mut [f32], nb_channels: usize) {
+        let nb_channels = nb_channels.max(1);
+        for channel in 0..nb_channels {
+            let (mut x1, mut x2, mut y1, mut y2) = (0f32, 0f32, 0f32, 0f32);
+            for x in samples.iter_mut().skip(channel).step_by(nb_channels) {
+                let y = self.b[0] * *x + self.b[1] * x1 + self.b[2] * x2
+                    - self.a[0] * y1
+                    - self.a[1] * y2;
+                (x2, x1, y2, y1) = (x1, *x, y1, y);
+                *x = y;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 44100;
+
+    fn run(processor: ProcessorConfig, samples: &mut [f32]) {
+        let mixture = samples.to_vec();
+        let context = StemContext {
+            track_name: "vocals",
+            sample_rate: SAMPLE_RATE,
+            nb_channels: 2,
+            mixture: &mixture,
+        };
+        processor.process(samples, &context).unwrap();
+    }
+
+    /// Interleaved stereo sine of `amplitude` lasting `frames`.
+    fn sine(frequency: f32, amplitude: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let x = amplitude * (2. * PI * frequency * i as f32 / SAMPLE_RATE as f32).sin();
+                [x, x]
+            })
+            .collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0f32, |peak, x| peak.max(x.abs()))
+    }
+
+    #[test]
+    fn high_pass_removes_dc() {
+        let mut samples = vec![0.5; 2 * SAMPLE_RATE];
+        run(ProcessorConfig::HighPass { cutoff_hz: 100. }, &mut samples);
+        assert!(peak(&samples[SAMPLE_RATE..]) < 1e-4);
+    }
+
+    #[test]
+    fn low_pass_keeps_dc_and_cuts_highs() {
+        let mut samples = vec![0.5; 2 * SAMPLE_RATE];
+        run(ProcessorConfig::LowPass { cutoff_hz: 1000. }, &mut samples);
+        assert!(samples[SAMPLE_RATE..]
+            .iter()
+            .all(|x| (x - 0.5).abs() < 1e-4));
+
+        // Two octaves above the cutoff, 24 dB down.
+        let mut samples = sine(4000., 0.5, SAMPLE_RATE);
+        run(ProcessorConfig::LowPass { cutoff_hz: 1000. }, &mut samples);
+        assert!(peak(&samples[SAMPLE_RATE..]) < 0.5 * db_to_gain(-23.));
+    }
+
+    #[test]
+    fn gate_silences_stems_below_threshold() {
+        let gate = ProcessorConfig::Gate {
+            threshold_db: -30.,
+            release_ms: 100.,
+        };
+        // -40 dB stays silent.
+        let mut samples = sine(440., 0.01, SAMPLE_RATE);
+        run(gate.clone(), &mut samples);
+        assert!(samples.iter().all(|x| *x == 0.));
+
+        // -6 dB opens the gate within a few milliseconds.
+        let input = sine(440., 0.5, SAMPLE_RATE);
+        let mut samples = input.clone();
+        run(gate, &mut samples);
+        let opened = 2 * SAMPLE_RATE / 100;
+        for (x, y) in samples[opened..].iter().zip(&input[opened..]) {
+            assert!((x - y).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn gain_scales_samples() {
+        let mut samples = sine(440., 0.5, 100);
+        let input = samples.clone();
+        run(ProcessorConfig::Gain { db: -6.0206 }, &mut samples);
+        for (x, y) in samples.iter().zip(&input) {
+            assert!((x - y / 2.).abs() < 1e-5);
+        }
+    }
+}
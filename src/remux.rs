@@ -0,0 +1,221 @@
+//! Put separated audio back into the source video.
+use anyhow::{Context, Result};
+use camino::Utf8Path as Path;
+use rsmpeg::{
+    avformat::{AVFormatContextInput, AVFormatContextOutput},
+    avutil::AVRational,
+    ffi::{self},
+    UnsafeDerefMut,
+};
+use std::ffi::CString;
+
+use crate::control::{Control, Progress};
+use crate::encode::{self, EncodeOptions, StreamEncoder};
+use crate::error::Error;
+use crate::utils::AudioInfo;
+use crate::utils::AudioParameters;
+
+fn to_seconds(timestamp: i64, time_base: AVRational) -> f64 {
+    timestamp as f64 * time_base.num as f64 / time_base.den as f64
+}
+
+/// Encoder of the replacement audio, fed in step with the copied packets.
+struct AudioFeeder<'a> {
+    stream_encoder: StreamEncoder,
+    pcm_audio_info: &'a AudioInfo,
+    pcm_data: &'a [u8],
+    /// Bytes of `pcm_data` already encoded.
+    offset: usize,
+    start_seconds: f64,
+}
+
+impl AudioFeeder<'_> {
+    /// Encode audio until it covers `seconds`, `None` encodes everything.
+    fn feed(
+        &mut self,
+        output_format_context: &mut AVFormatContextOutput,
+        seconds: Option<f64>,
+    ) -> Result<()> {
+        let sample_size =
+            self.pcm_audio_info.sample_size * self.pcm_audio_info.ch_layout.nb_channels as usize;
+        let chunk_size = self.pcm_audio_info.sample_rate * sample_size;
+        while self.offset < self.pcm_data.len() {
+            let fed_seconds = self.start_seconds
+                + (self.offset / sample_size) as f64 / self.pcm_audio_info.sample_rate as f64;
+            if matches!(seconds, Some(seconds) if fed_seconds > seconds) {
+                break;
+            }
+            let end = (self.offset + chunk_size).min(self.pcm_data.len());
+            self.stream_encoder.encode(
+                output_format_context,
+                self.pcm_audio_info,
+                &self.pcm_data[self.offset..end],
+            )?;
+            self.offset = end;
+        }
+        Ok(())
+    }
+}
+
+/// Copy every non-audio stream of `input_path` into `output_path` untouched,
+/// replacing its main audio stream with `pcm_data`.
+///
+/// Other audio streams are dropped.
+pub fn remux_with_audio(
+    input_path: &Path,
+    pcm_data: &[u8],
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+    output_path: &Path,
+) -> Result<(), Error> {
+    remux(
+        input_path,
+        pcm_data,
+        pcm_audio_info,
+        audio_parameters,
+        options,
+        control,
+        output_path,
+    )
+    .map_err(Error::wrap(Error::Encode))
+}
+
+fn remux(
+    input_path: &Path,
+    pcm_data: &[u8],
+    pcm_audio_info: &AudioInfo,
+    audio_parameters: &AudioParameters,
+    options: &EncodeOptions,
+    control: &Control,
+    output_path: &Path,
+) -> Result<()> {
+    let input_path = CString::new(input_path.as_str()).unwrap();
+    let mut input_format_context =
+        AVFormatContextInput::open(&input_path).context("Open input file failed.")?;
+    let (audio_index, _) = input_format_context
+        .find_best_stream(ffi::AVMEDIA_TYPE_AUDIO)
+        .context("Find best stream failed.")?
+        .context("Cannot find audio stream in this file.")?;
+    // Cover art of audio files is an attached picture, not a video.
+    let has_video = input_format_context.streams().iter().any(|x| {
+        x.codecpar().codec_type == ffi::AVMEDIA_TYPE_VIDEO
+            && x.disposition & ffi::AV_DISPOSITION_ATTACHED_PIC as i32 == 0
+    });
+    if !has_video {
+        return Err(Error::Input("No video stream to copy, drop --remux-video.".into()).into());
+    }
+
+    let mut output_format_context = encode::create_output_format_context(output_path)?;
+
+    // Input stream index -> output stream index of copied streams.
+    let mut stream_map = vec![None; input_format_context.streams().len()];
+    let mut audio_feeder = None;
+    for input_stream in input_format_context.streams() {
+        let index = input_stream.index as usize;
+        let codec_type = input_stream.codecpar().codec_type;
+        if index == audio_index {
+            let mut stream_encoder = StreamEncoder::new(
+                &mut output_format_context,
+                pcm_audio_info,
+                audio_parameters,
+                options,
+                true,
+                None,
+                input_stream.disposition,
+            )
+            .context("Create audio stream failed.")?;
+            let start_time = match input_stream.start_time {
+                ffi::AV_NOPTS_VALUE => 0,
+                start_time => start_time,
+            };
+            stream_encoder.set_start_time(start_time, input_stream.time_base);
+            audio_feeder = Some(AudioFeeder {
+                stream_encoder,
+                pcm_audio_info,
+                pcm_data,
+                offset: 0,
+                start_seconds: to_seconds(start_time, input_stream.time_base),
+            });
+        } else if codec_type != ffi::AVMEDIA_TYPE_AUDIO {
+            let mut codecpar = input_stream.codecpar().clone();
+            // The tag of the input container may be invalid in the output.
+            unsafe { codecpar.deref_mut() }.codec_tag = 0;
+            let mut output_stream = output_format_context.new_stream();
+            output_stream.set_codecpar(codecpar);
+            output_stream.set_time_base(input_stream.time_base);
+            unsafe { output_stream.deref_mut() }.disposition = input_stream.disposition;
+            stream_map[index] = Some(output_stream.index as usize);
+        }
+    }
+    let mut audio_feeder = audio_feeder.context("Audio stream not found.")?;
+
+    output_format_context
+        .write_header(&mut None)
+        .context("Write header failed.")?;
+
+    let duration = pcm_data.len() as f64
+        / (pcm_audio_info.sample_rate
+            * pcm_audio_info.sample_size
+            * pcm_audio_info.ch_layout.nb_channels as usize) as f64;
+    let mut reported_seconds = f64::NEG_INFINITY;
+    while let Some(mut packet) = input_format_context
+        .read_packet()
+        .context("Read packet failed.")?
+    {
+        control.check()?;
+        let input_index = packet.stream_index as usize;
+        let output_index = match stream_map[input_index] {
+            Some(output_index) => output_index,
+            None => continue,
+        };
+        let input_time_base = input_format_context
+            .streams()
+            .get(input_index)
+            .unwrap()
+            .time_base;
+        let timestamp = match packet.dts {
+            ffi::AV_NOPTS_VALUE => packet.pts,
+            dts => dts,
+        };
+        // Keep the audio about a second ahead of the copied streams.
+        if timestamp != ffi::AV_NOPTS_VALUE {
+            let seconds = to_seconds(timestamp, input_time_base);
+            audio_feeder.feed(&mut output_format_context, Some(seconds + 1.))?;
+            if seconds >= reported_seconds + 1. {
+                reported_seconds = seconds;
+                control.report(Progress::Encode {
+                    track: None,
+                    seconds: (seconds - audio_feeder.start_seconds).clamp(0., duration),
+                    duration,
+                });
+            }
+        }
+
+        packet.rescale_ts(
+            input_time_base,
+            output_format_context
+                .streams()
+                .get(output_index)
+                .unwrap()
+                .time_base,
+        );
+        packet.set_stream_index(output_index as i32);
+        packet.set_pos(-1);
+        output_format_context
+            .interleaved_write_frame(&mut packet)
+            .context("Write copied packet failed.")?;
+    }
+
+    audio_feeder.feed(&mut output_format_context, None)?;
+    audio_feeder
+        .stream_encoder
+        .finish(&mut output_format_context)?;
+
+    output_format_context
+        .write_trailer()
+        .context("Write trailer failed.")?;
+
+    Ok(())
+}
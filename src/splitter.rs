@@ -1,13 +1,23 @@
 use anyhow::{Context, Result};
-use camino::Utf8Path as Path;
+use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
 use once_cell::sync::Lazy;
-use tensorflow::SessionOptions;
-use tensorflow::SessionRunArgs;
-use tensorflow::Tensor;
-use tensorflow::{Graph, SavedModelBundle};
+use std::ops::Deref;
+#[cfg(feature = "tensorflow")]
+use tensorflow::{Graph, SavedModelBundle, Session, SessionOptions, SessionRunArgs, Tensor};
 use tracing::info;
+#[cfg(feature = "mock")]
+use tracing::warn;
 
-use crate::utils::AudioData;
+use crate::control::{Control, Progress};
+use crate::decode;
+use crate::encode::{self, EncodeOptions};
+use crate::error::Error;
+#[cfg(feature = "mock")]
+use crate::mock::{self, MockBehavior};
+use crate::models;
+use crate::output::{self, OverwritePolicy};
+use crate::processors::{StemContext, StemProcessors};
+use crate::utils::{self, AudioData, AudioInfo};
 
 pub struct SpleeterModelInfo {
     pub name: &'static str,
@@ -80,80 +90,357 @@ static MODEL_INFOS: Lazy<[SpleeterModelInfo; 6]> = Lazy::new(|| {
     ]
 });
 
-pub fn existing_models() -> Vec<&'static str> {
-    MODEL_INFOS.iter().map(|x| x.name).collect()
+/// Models `Separator::load` can load: installed in `models_dir` or embedded,
+/// every known model when the mock backend is enabled.
+pub fn existing_models(models_dir: &Path) -> Vec<&'static str> {
+    #[cfg(feature = "mock")]
+    if let Ok(Some(_)) = mock::from_env() {
+        return MODEL_INFOS.iter().map(|x| x.name).collect();
+    }
+    models::list(models_dir)
+        .into_iter()
+        .filter(|x| x.installed || x.embedded)
+        .map(|x| x.name)
+        .collect()
 }
 
 impl SpleeterModelInfo {
+    pub fn all() -> impl Iterator<Item = &'static SpleeterModelInfo> {
+        MODEL_INFOS.iter()
+    }
+
     pub fn get_by_name(model_name: &str) -> Option<&'static SpleeterModelInfo> {
         MODEL_INFOS.iter().find(|info| info.name == model_name)
     }
 }
 
-pub fn split_pcm_audio(
-    audio_data: &AudioData,
-    model_info: &SpleeterModelInfo,
-    models_dir: &Path,
-) -> Result<Vec<Vec<f32>>> {
-    let tensorflow_version = tensorflow::version().unwrap();
-    info!(?tensorflow_version);
-
-    let slice_length = audio_data.sample_rate * 30;
-    let extend_length = audio_data.sample_rate * 5;
-    let nb_channels = audio_data.nb_channels;
-
-    let mut transformed_samples = vec![vec![]; model_info.output_count];
-
-    let model_path = models_dir.join(model_info.name);
-    let mut graph = Graph::new();
-    let session = SavedModelBundle::load(&SessionOptions::new(), ["serve"], &mut graph, model_path)
-        .context("Cannot load session")?
-        .session;
-
-    let input_samples_count_per_channel = audio_data.samples.len() / audio_data.nb_channels;
-    let segment_count = (input_samples_count_per_channel + (slice_length - 1)) / slice_length;
-
-    for i in 0..segment_count {
-        let current_offset = slice_length * i;
-        let extend_length_at_begin = if i == 0 { 0 } else { extend_length };
-        let extend_length_at_end = if i == (segment_count - 1) {
-            0
-        } else {
-            extend_length
-        };
+/// Default length of the segments fed to the model, in seconds.
+pub const DEFAULT_SEGMENT_LENGTH: usize = 30;
+/// Default context added on both sides of a segment, in seconds.
+pub const DEFAULT_SEGMENT_OVERLAP: usize = 5;
 
-        let useful_start = extend_length_at_begin;
-        let useful_length = if i == (segment_count - 1) {
-            input_samples_count_per_channel - current_offset
-        } else {
-            slice_length
-        };
+enum Backend {
+    #[cfg(feature = "tensorflow")]
+    TensorFlow { graph: Graph, session: Session },
+    #[cfg(feature = "mock")]
+    Mock(MockBehavior),
+}
+
+/// Interleaved samples of one stem from the backend, TensorFlow's output
+/// tensors are kept rather than copied.
+pub(crate) enum StemOutput {
+    #[cfg(feature = "tensorflow")]
+    Tensor(Tensor<f32>),
+    #[cfg(feature = "mock")]
+    Samples(Vec<f32>),
+}
+
+impl Deref for StemOutput {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        match self {
+            #[cfg(feature = "tensorflow")]
+            Self::Tensor(tensor) => tensor,
+            #[cfg(feature = "mock")]
+            Self::Samples(samples) => samples,
+        }
+    }
+}
+
+/// A loaded model, reusable for any number of inputs.
+pub struct Separator {
+    model_info: &'static SpleeterModelInfo,
+    backend: Backend,
+    segment_length: usize,
+    segment_overlap: usize,
+    processors: StemProcessors,
+}
+
+impl Separator {
+    pub fn load(model_info: &'static SpleeterModelInfo, models_dir: &Path) -> Result<Self, Error> {
+        #[cfg(feature = "mock")]
+        if let Some(behavior) = mock::from_env().map_err(|e| Error::Model(e.into()))? {
+            warn!("Using the mock backend: {}", behavior);
+            return Ok(Self::mock(model_info, behavior));
+        }
+        Self::load_tensorflow(model_info, models_dir)
+    }
+
+    #[cfg(feature = "tensorflow")]
+    fn load_tensorflow(
+        model_info: &'static SpleeterModelInfo,
+        models_dir: &Path,
+    ) -> Result<Self, Error> {
+        let tensorflow_version = tensorflow::version().unwrap();
+        info!(?tensorflow_version);
+
+        let model_path =
+            models::model_path(model_info, models_dir).map_err(|e| Error::Model(e.into()))?;
+        if !model_path.is_dir() {
+            return Err(Error::Model(
+                format!("Model not found: {}", model_path).into(),
+            ));
+        }
+        let mut graph = Graph::new();
+        let session =
+            SavedModelBundle::load(&SessionOptions::new(), ["serve"], &mut graph, model_path)
+                .context("Cannot load session")
+                .map_err(|e| Error::Model(e.into()))?
+                .session;
+        Ok(Self::new(
+            model_info,
+            Backend::TensorFlow { graph, session },
+        ))
+    }
+
+    #[cfg(not(feature = "tensorflow"))]
+    fn load_tensorflow(
+        _model_info: &'static SpleeterModelInfo,
+        _models_dir: &Path,
+    ) -> Result<Self, Error> {
+        Err(Error::Model(
+            "Built without the `tensorflow` feature.".into(),
+        ))
+    }
+
+    /// A separator computing stems with `behavior` instead of a model, check
+    /// `mock`.
+    #[cfg(feature = "mock")]
+    pub fn mock(model_info: &'static SpleeterModelInfo, behavior: MockBehavior) -> Self {
+        Self::new(model_info, Backend::Mock(behavior))
+    }
+
+    fn new(model_info: &'static SpleeterModelInfo, backend: Backend) -> Self {
+        Self {
+            model_info,
+            backend,
+            segment_length: DEFAULT_SEGMENT_LENGTH,
+            segment_overlap: DEFAULT_SEGMENT_OVERLAP,
+            processors: StemProcessors::new(),
+        }
+    }
+
+    /// Process audio in segments of `length` seconds, each with `overlap`
+    /// seconds of context on both sides. Longer segments use more memory.
+    ///
+    /// `length` is at least 1 and `overlap` shorter than `length`, larger
+    /// values are clamped.
+    pub fn with_segmenting(mut self, length: usize, overlap: usize) -> Self {
+        self.segment_length = length.max(1);
+        self.segment_overlap = overlap.min(self.segment_length - 1);
+        self
+    }
+
+    /// Run `processors` on the stems returned by `separate` and its
+    /// variants, `separate_segments` returns them unprocessed.
+    pub fn with_processors(mut self, processors: StemProcessors) -> Self {
+        self.processors = processors;
+        self
+    }
+
+    pub fn model_info(&self) -> &'static SpleeterModelInfo {
+        self.model_info
+    }
+
+    /// Segment length in seconds.
+    pub fn segment_length(&self) -> usize {
+        self.segment_length
+    }
+
+    /// Context on both sides of a segment in seconds.
+    pub fn segment_overlap(&self) -> usize {
+        self.segment_overlap
+    }
 
-        let process_start = current_offset - extend_length_at_begin;
-        let process_length = (useful_length + extend_length_at_begin + extend_length_at_end)
-            .min(input_samples_count_per_channel - process_start);
+    pub fn separate(&self, audio_data: &AudioData) -> Result<Vec<Vec<f32>>, Error> {
+        self.separate_with_control(audio_data, &Control::default())
+    }
+
+    /// Separate with `control` checked before each segment and receiving
+    /// `Progress::Segment` after each one.
+    pub fn separate_with_control(
+        &self,
+        audio_data: &AudioData,
+        control: &Control,
+    ) -> Result<Vec<Vec<f32>>, Error> {
+        self.separate_interleaved(
+            &audio_data.samples,
+            audio_data.nb_channels,
+            audio_data.sample_rate,
+            control,
+        )
+    }
+
+    /// `separate_with_control` on borrowed interleaved samples.
+    pub fn separate_interleaved(
+        &self,
+        samples: &[f32],
+        nb_channels: usize,
+        sample_rate: usize,
+        control: &Control,
+    ) -> Result<Vec<Vec<f32>>, Error> {
+        let mut transformed_samples = vec![vec![]; self.model_info.output_count];
+        self.run(samples, nb_channels, sample_rate, control, |stems| {
+            for (samples, stem) in transformed_samples.iter_mut().zip(stems) {
+                samples.extend_from_slice(stem);
+            }
+            Ok(())
+        })
+        .map_err(Error::wrap(Error::Inference))?;
+
+        for (track_name, stem) in self
+            .model_info
+            .track_names
+            .iter()
+            .zip(&mut transformed_samples)
+        {
+            let context = StemContext {
+                track_name,
+                sample_rate,
+                nb_channels,
+                mixture: samples,
+            };
+            self.processors
+                .process(stem, &context)
+                .with_context(|| format!("Process {} failed.", track_name))
+                .map_err(Error::wrap(Error::Inference))?;
+        }
+        Ok(transformed_samples)
+    }
+
+    /// Separate segment by segment, handing the interleaved stems of each
+    /// segment to `on_segment` as soon as they are ready instead of
+    /// collecting them. An error from `on_segment` stops the separation.
+    pub fn separate_segments(
+        &self,
+        audio_data: &AudioData,
+        control: &Control,
+        on_segment: impl FnMut(Vec<&[f32]>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        self.run(
+            &audio_data.samples,
+            audio_data.nb_channels,
+            audio_data.sample_rate,
+            control,
+            on_segment,
+        )
+        .map_err(Error::wrap(Error::Inference))
+    }
+
+    fn run(
+        &self,
+        samples: &[f32],
+        nb_channels: usize,
+        sample_rate: usize,
+        control: &Control,
+        mut on_segment: impl FnMut(Vec<&[f32]>) -> Result<(), Error>,
+    ) -> Result<()> {
+        let slice_length = sample_rate * self.segment_length;
+        let extend_length = sample_rate * self.segment_overlap;
+
+        let input_samples_count_per_channel = samples.len() / nb_channels;
+        let segment_count = (input_samples_count_per_channel + (slice_length - 1)) / slice_length;
+
+        for i in 0..segment_count {
+            control.check()?;
+            let current_offset = slice_length * i;
+            let extend_length_at_begin = if i == 0 { 0 } else { extend_length };
+            let extend_length_at_end = if i == (segment_count - 1) {
+                0
+            } else {
+                extend_length
+            };
+
+            let useful_start = extend_length_at_begin;
+            let useful_length = if i == (segment_count - 1) {
+                input_samples_count_per_channel - current_offset
+            } else {
+                slice_length
+            };
+
+            let process_start = current_offset - extend_length_at_begin;
+            let process_length = (useful_length + extend_length_at_begin + extend_length_at_end)
+                .min(input_samples_count_per_channel - process_start);
+
+            info!(
+                "processing: [{}, {}), using [{}, {})",
+                process_start,
+                process_start + process_length,
+                current_offset,
+                current_offset + useful_length
+            );
+
+            let input_data_length = process_length * nb_channels;
+            let input_data_begin = process_start * nb_channels;
+            let input_data = &samples[input_data_begin..input_data_begin + input_data_length];
+            let outputs = self.infer(input_data, nb_channels, sample_rate)?;
+
+            let begin = useful_start * nb_channels;
+            let len = useful_length * nb_channels;
+            on_segment(
+                outputs
+                    .iter()
+                    .map(|data| &data[begin..begin + len])
+                    .collect(),
+            )?;
+            info!("{}/{} done...", i + 1, segment_count);
+            control.report(Progress::Segment {
+                segment: i + 1,
+                segments: segment_count,
+            });
+        }
+        Ok(())
+    }
 
-        info!(
-            "processing: [{}, {}), using [{}, {})",
-            process_start,
-            process_start + process_length,
-            current_offset,
-            current_offset + useful_length
-        );
+    /// Run the model once on interleaved `input` at `sample_rate`, returning
+    /// the interleaved samples of every stem, of the same length.
+    ///
+    /// Only the mock uses `sample_rate`, the models always take 44.1 kHz.
+    #[cfg_attr(not(feature = "mock"), allow(unused_variables))]
+    pub(crate) fn infer(
+        &self,
+        input: &[f32],
+        nb_channels: usize,
+        sample_rate: usize,
+    ) -> Result<Vec<StemOutput>> {
+        match &self.backend {
+            #[cfg(feature = "tensorflow")]
+            Backend::TensorFlow { graph, session } => {
+                self.infer_tensorflow(graph, session, input, nb_channels)
+            }
+            #[cfg(feature = "mock")]
+            Backend::Mock(behavior) => Ok(behavior
+                .infer(
+                    input,
+                    nb_channels,
+                    sample_rate,
+                    self.model_info.output_count,
+                )
+                .into_iter()
+                .map(StemOutput::Samples)
+                .collect()),
+        }
+    }
+
+    #[cfg(feature = "tensorflow")]
+    fn infer_tensorflow(
+        &self,
+        graph: &Graph,
+        session: &Session,
+        input: &[f32],
+        nb_channels: usize,
+    ) -> Result<Vec<StemOutput>> {
+        let model_info = self.model_info;
 
         let oper = graph
             .operation_by_name("Placeholder")
             .context("Get operation failed")?
             .context("Get empty operation")?;
-        let input_dims = [process_length as u64, nb_channels as u64];
-
-        let input_data_length = process_length * nb_channels;
-        let input_data_begin = process_start * nb_channels;
-        let input_data =
-            &audio_data.samples[input_data_begin..input_data_begin + input_data_length];
+        let input_dims = [(input.len() / nb_channels) as u64, nb_channels as u64];
 
         let input_tensors = Tensor::new(&input_dims)
-            .with_values(input_data)
+            .with_values(input)
             .context("Get tensor failed.")?;
 
         let mut output_tokens = Vec::new();
@@ -172,15 +459,77 @@ pub fn split_pcm_audio(
 
         session.run(&mut run_args).context("Run session failed")?;
 
-        for i in 0..model_info.output_count {
-            let data: Tensor<f32> = run_args
-                .fetch(output_tokens[i])
-                .context("Get output failed")?;
-            let begin = useful_start * nb_channels;
-            let len = useful_length * nb_channels;
-            transformed_samples[i].extend_from_slice(&data.as_ref()[begin..begin + len]);
+        let mut outputs = Vec::new();
+        for output_token in output_tokens {
+            let data: Tensor<f32> = run_args.fetch(output_token).context("Get output failed")?;
+            outputs.push(StemOutput::Tensor(data));
         }
-        info!("{}/{} done...", i + 1, segment_count);
+        Ok(outputs)
     }
-    Ok(transformed_samples)
+
+    /// Decode `input`, separate it, run the processors and write every stem
+    /// to `<out_dir>/<track>.<extension>`, `extension` defaults to the
+    /// input's. Existing outputs are handled according to `policy`.
+    ///
+    /// Returns (track name, path) of each stem, including the ones kept by
+    /// `OverwritePolicy::SkipExisting`.
+    pub fn separate_file(
+        &self,
+        input: &Path,
+        out_dir: &Path,
+        extension: Option<&str>,
+        options: &EncodeOptions,
+        policy: OverwritePolicy,
+        control: &Control,
+    ) -> Result<Vec<(&'static str, PathBuf)>, Error> {
+        let extension = match extension.or_else(|| input.extension()) {
+            Some(extension) => extension,
+            None => return Err(Error::Input(format!("No extension: {}", input).into())),
+        };
+        let pcm_audio_info = AudioInfo::new_pcm(44100);
+        let (audio_parameters, pcm_data) =
+            decode::decode_audio(input, &pcm_audio_info, false, control)?;
+        let audio_data = AudioData::from_pcm(&pcm_data, &pcm_audio_info);
+        drop(pcm_data);
+        let stems = self.separate_with_control(&audio_data, control)?;
+
+        let mut outputs = Vec::new();
+        for (track_name, samples) in self.model_info.track_names.iter().zip(&stems) {
+            let output_path = out_dir.join(format!("{}.{}", track_name, extension));
+            let pcm_data = utils::samples_to_pcm(samples);
+            output::write_atomically(&output_path, policy, |output_path| {
+                encode::encode_pcm_data(
+                    &pcm_data,
+                    &pcm_audio_info,
+                    &audio_parameters,
+                    options,
+                    &control.for_track(track_name),
+                    output_path,
+                )
+                .context("Encode pcm data failed.")
+            })
+            .map_err(Error::wrap(Error::Encode))?;
+            outputs.push((*track_name, output_path));
+        }
+        Ok(outputs)
+    }
+}
+
+pub fn split_pcm_audio(
+    audio_data: &AudioData,
+    model_info: &'static SpleeterModelInfo,
+    models_dir: &Path,
+) -> Result<Vec<Vec<f32>>, Error> {
+    Separator::load(model_info, models_dir)?.separate(audio_data)
+}
+
+/// `split_pcm_audio` with progress reporting and cancellation.
+pub fn split_pcm_audio_with_control(
+    audio_data: &AudioData,
+    model_info: &'static SpleeterModelInfo,
+    models_dir: &Path,
+    control: &Control,
+) -> Result<Vec<Vec<f32>>, Error> {
+    control.check()?;
+    Separator::load(model_info, models_dir)?.separate_with_control(audio_data, control)
 }
@@ -0,0 +1,194 @@
+//! Push-based separation of unbounded streams, e.g. live feeds.
+//!
+//! Input is segmented exactly like `Separator::separate`, so a stream pushed
+//! in blocks of any size gives the same stems as separating it at once.
+use anyhow::Result;
+use std::{mem, sync::Arc};
+
+use crate::error::Error;
+use crate::splitter::Separator;
+
+/// Separates blocks of interleaved samples as they arrive.
+///
+/// A segment is separated once its frames and the context after it are
+/// pushed. Stems are queued so that they come out exactly `latency()` frames
+/// behind the input: `push` returns as many frames as it is given, the first
+/// `latency()` of them silent, and `finish` returns the last `latency()`.
+pub struct StreamingSeparator {
+    separator: Arc<Separator>,
+    nb_channels: usize,
+    sample_rate: usize,
+    /// Frames per segment.
+    segment_frames: usize,
+    /// Frames of context on both sides of a segment.
+    overlap_frames: usize,
+    /// Interleaved input from frame `buffer_start` on, earlier frames are no
+    /// longer needed.
+    buffer: Vec<f32>,
+    buffer_start: usize,
+    /// Next segment to separate.
+    segment: usize,
+    /// Interleaved stems not returned yet, after `latency()` frames of
+    /// silence at the start of a stream.
+    output: Vec<Vec<f32>>,
+}
+
+/// State to restore when a call fails halfway.
+struct Checkpoint {
+    buffer_len: usize,
+    segment: usize,
+    output_len: usize,
+}
+
+impl StreamingSeparator {
+    /// Stream of `nb_channels` interleaved channels at `sample_rate`,
+    /// segmented as configured with `Separator::with_segmenting`.
+    pub fn new(separator: Arc<Separator>, nb_channels: usize, sample_rate: usize) -> Self {
+        let segment_frames = separator.segment_length() * sample_rate;
+        let overlap_frames = separator.segment_overlap() * sample_rate;
+        let mut streaming = Self {
+            separator,
+            nb_channels,
+            sample_rate,
+            segment_frames,
+            overlap_frames,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            segment: 0,
+            output: Vec::new(),
+        };
+        streaming.reset();
+        streaming
+    }
+
+    pub fn separator(&self) -> &Separator {
+        &self.separator
+    }
+
+    /// Delay between pushing a frame and getting its stems, in frames: one
+    /// segment plus its context.
+    pub fn latency(&self) -> usize {
+        self.segment_frames + self.overlap_frames
+    }
+
+    /// Frames pushed since the stream started.
+    fn received(&self) -> usize {
+        self.buffer_start + self.buffer.len() / self.nb_channels
+    }
+
+    /// Append interleaved `samples`, returning as many frames of every stem,
+    /// `latency()` frames behind.
+    ///
+    /// On error nothing is kept, the block can be pushed again.
+    pub fn push(&mut self, samples: &[f32]) -> Result<Vec<Vec<f32>>, Error> {
+        if samples.len() % self.nb_channels != 0 {
+            return Err(Error::Input(
+                format!("Block of {} samples is not whole frames.", samples.len()).into(),
+            ));
+        }
+        let checkpoint = self.checkpoint();
+        self.buffer.extend_from_slice(samples);
+        while self.received() >= (self.segment + 1) * self.segment_frames + self.overlap_frames {
+            if let Err(e) = self.separate_segment(None) {
+                self.rollback(checkpoint);
+                return Err(Error::wrap(Error::Inference)(e));
+            }
+        }
+        self.drop_used_input();
+        Ok(self
+            .output
+            .iter_mut()
+            .map(|stem| stem.drain(..samples.len()).collect())
+            .collect())
+    }
+
+    /// End the stream, returning the last `latency()` frames of every stem.
+    /// The separator is then ready for a new stream.
+    ///
+    /// On error the stream is kept, call `finish` again or `reset`.
+    pub fn finish(&mut self) -> Result<Vec<Vec<f32>>, Error> {
+        let total = self.received();
+        let checkpoint = self.checkpoint();
+        while self.segment * self.segment_frames < total {
+            if let Err(e) = self.separate_segment(Some(total)) {
+                self.rollback(checkpoint);
+                return Err(Error::wrap(Error::Inference)(e));
+            }
+        }
+        let stems = mem::take(&mut self.output);
+        self.reset();
+        Ok(stems)
+    }
+
+    /// Drop the buffered input and start a new stream.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.buffer_start = 0;
+        self.segment = 0;
+        let silence = vec![0.; self.latency() * self.nb_channels];
+        self.output = vec![silence; self.separator.model_info().output_count];
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            buffer_len: self.buffer.len(),
+            segment: self.segment,
+            output_len: self.output[0].len(),
+        }
+    }
+
+    fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.buffer.truncate(checkpoint.buffer_len);
+        self.segment = checkpoint.segment;
+        for stem in &mut self.output {
+            stem.truncate(checkpoint.output_len);
+        }
+    }
+
+    /// Drop the input before the context of the next segment.
+    fn drop_used_input(&mut self) {
+        let keep_from = (self.segment * self.segment_frames).saturating_sub(self.overlap_frames);
+        if keep_from > self.buffer_start {
+            self.buffer
+                .drain(..(keep_from - self.buffer_start) * self.nb_channels);
+            self.buffer_start = keep_from;
+        }
+    }
+
+    /// Separate the next segment into `output`, `total` is the stream length
+    /// once it ended.
+    fn separate_segment(&mut self, total: Option<usize>) -> Result<()> {
+        let nb_channels = self.nb_channels;
+        let current_offset = self.segment * self.segment_frames;
+        let last = total.map_or(false, |total| current_offset + self.segment_frames >= total);
+        let extend_length_at_begin = if self.segment == 0 {
+            0
+        } else {
+            self.overlap_frames
+        };
+        let extend_length_at_end = if last { 0 } else { self.overlap_frames };
+        let useful_length = match total {
+            Some(total) if last => total - current_offset,
+            _ => self.segment_frames,
+        };
+
+        let process_start = current_offset - extend_length_at_begin;
+        let process_length = (useful_length + extend_length_at_begin + extend_length_at_end)
+            .min(self.received() - process_start);
+
+        let input_data_begin = (process_start - self.buffer_start) * nb_channels;
+        let input_data =
+            &self.buffer[input_data_begin..input_data_begin + process_length * nb_channels];
+        let outputs = self
+            .separator
+            .infer(input_data, nb_channels, self.sample_rate)?;
+
+        let begin = extend_length_at_begin * nb_channels;
+        let len = useful_length * nb_channels;
+        for (stem, data) in self.output.iter_mut().zip(&outputs) {
+            stem.extend_from_slice(&data[begin..begin + len]);
+        }
+        self.segment += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+//! Output path templates such as `{input_stem}/{model}/{track}.{ext}`.
+//!
+//! Placeholders:
+//! - `{input_stem}`: input file name without extension
+//! - `{input_name}`: input file name
+//! - `{model}`: model name
+//! - `{track}`: stem name
+//! - `{index}`: stem index, starting from 1
+//! - `{ext}`: output extension
+//! - `{date}`: current date as `YYYY-MM-DD` (UTC)
+//! - `{hash}`: first 8 hex digits of the input's SHA-256
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Component, Utf8Path as Path, Utf8PathBuf as PathBuf};
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::utils;
+
+/// Values shared by all outputs of one input.
+pub struct TemplateContext<'a> {
+    pub input: &'a Path,
+    pub model: &'a str,
+    date: String,
+    hash: Option<String>,
+}
+
+impl<'a> TemplateContext<'a> {
+    /// `template` is used to skip hashing the input when `{hash}` is unused.
+    pub fn new(input: &'a Path, model: &'a str, template: &str) -> Result<Self> {
+        let hash = if template.contains("{hash}") {
+            let hash = utils::sha256_file(input).context("Hash input failed.")?;
+            Some(hash[..8].to_owned())
+        } else {
+            None
+        };
+        Ok(Self {
+            input,
+            model,
+            date: today(),
+            hash,
+        })
+    }
+}
+
+/// Current UTC date as `YYYY-MM-DD`.
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() / 86400)
+        .unwrap_or_default() as i64;
+    // Check http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Make a placeholder value safe to use as a single path component.
+fn sanitize(value: &str) -> String {
+    let sanitized: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows ignores trailing dots and spaces.
+    let sanitized = sanitized.trim_end_matches(['.', ' ']);
+    match sanitized {
+        "" | "." | ".." => "_".to_owned(),
+        sanitized => sanitized.to_owned(),
+    }
+}
+
+/// Render `template` for one output, relative to `out_dir`.
+pub fn render(
+    template: &str,
+    context: &TemplateContext,
+    out_dir: &Path,
+    track: &str,
+    index: usize,
+    ext: &str,
+) -> Result<PathBuf> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(begin) = rest.find('{') {
+        rendered.push_str(&rest[..begin]);
+        let end = rest[begin..]
+            .find('}')
+            .with_context(|| format!("Unclosed placeholder in template: {}", template))?;
+        let name = &rest[begin + 1..begin + end];
+        let value = match name {
+            "input_stem" => context.input.file_stem().unwrap_or_default().to_owned(),
+            "input_name" => context.input.file_name().unwrap_or_default().to_owned(),
+            "model" => context.model.to_owned(),
+            "track" => track.to_owned(),
+            "index" => (index + 1).to_string(),
+            "ext" => ext.to_owned(),
+            "date" => context.date.clone(),
+            "hash" => context.hash.clone().unwrap_or_default(),
+            _ => bail!("Unknown placeholder {{{}}} in template: {}", name, template),
+        };
+        rendered.push_str(&sanitize(&value));
+        rest = &rest[begin + end + 1..];
+    }
+    rendered.push_str(rest);
+    // Placeholder values are sanitized, but literal text may still leave
+    // `out_dir`.
+    let rendered = PathBuf::from(rendered);
+    let inside = rendered
+        .components()
+        .all(|x| matches!(x, Utf8Component::Normal(_) | Utf8Component::CurDir));
+    if !inside || rendered.file_name().is_none() {
+        bail!(
+            "Template must render to a file inside the output dir: {}",
+            template
+        );
+    }
+    Ok(out_dir.join(rendered))
+}
+
+/// Fail if two outputs, of the same input or of different ones, render to
+/// the same path.
+pub fn check_collisions<'a>(
+    outputs: impl IntoIterator<Item = (&'a Path, &'a Vec<PathBuf>)>,
+) -> Result<()> {
+    let mut seen: HashMap<&Path, &Path> = HashMap::new();
+    for (input, output_paths) in outputs {
+        for output_path in output_paths {
+            if let Some(other) = seen.insert(output_path, input) {
+                bail!(
+                    "Outputs of {} and {} collide at {}, add placeholders such as {{track}} or {{hash}} to --output-template.",
+                    other,
+                    input,
+                    output_path
+                );
+            }
+        }
+    }
+    Ok(())
+}
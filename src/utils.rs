@@ -1,9 +1,21 @@
+use anyhow::{Context, Result};
+use camino::Utf8Path as Path;
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::{c_char, CStr},
+    fs::File,
+    io, ptr,
+    sync::Mutex,
+    thread,
+};
+
 use rsmpeg::{
     avcodec::{AVCodecContext, AVCodecParameters},
     avutil::{get_bytes_per_sample, AVChannelLayout, AVRational},
     ffi::{self},
 };
 
+#[derive(Clone)]
 pub struct AudioInfo {
     pub sample_rate: usize,
     pub sample_fmt: ffi::AVSampleFormat,
@@ -35,7 +47,7 @@ impl AudioInfo {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct AudioParameters {
     pub time_base: AVRational,
     pub codecpar: AVCodecParameters,
@@ -55,4 +67,116 @@ impl AudioData {
             samples,
         }
     }
+
+    /// From interleaved f32 pcm data as produced by `decode::decode_audio`.
+    pub fn from_pcm(pcm_data: &[u8], pcm_audio_info: &AudioInfo) -> Self {
+        let samples = pcm_data
+            .chunks_exact(4)
+            .map(|x| x.try_into().unwrap())
+            .map(f32::from_le_bytes)
+            .collect();
+        Self::new(
+            samples,
+            pcm_audio_info.ch_layout.nb_channels as usize,
+            pcm_audio_info.sample_rate,
+        )
+    }
+}
+
+/// Interleaved f32 samples as pcm data for `encode`.
+pub fn samples_to_pcm(samples: &[f32]) -> Vec<u8> {
+    samples.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+/// Run `f` on every item with at most `jobs` threads, results keep the items'
+/// order.
+pub fn parallel_map<T, R, F>(items: Vec<T>, jobs: usize, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let count = items.len();
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let results = Mutex::new((0..count).map(|_| None).collect::<Vec<_>>());
+    thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, count.max(1)) {
+            s.spawn(|| loop {
+                // Release the queue lock before running the job.
+                let next = queue.lock().unwrap().next();
+                let (i, item) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let result = f(item);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|x| x.unwrap())
+        .collect()
+}
+
+pub fn codec_name(codec_id: ffi::AVCodecID) -> String {
+    unsafe { CStr::from_ptr(ffi::avcodec_get_name(codec_id)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn sample_fmt_name(sample_fmt: ffi::AVSampleFormat) -> String {
+    let name = unsafe { ffi::av_get_sample_fmt_name(sample_fmt) };
+    if name.is_null() {
+        return "none".to_owned();
+    }
+    unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Channel layout name, e.g. `stereo` or `5.1(side)`.
+pub fn describe_ch_layout(ch_layout: &ffi::AVChannelLayout) -> String {
+    let mut buf = [0 as c_char; 64];
+    let ret = unsafe { ffi::av_channel_layout_describe(ch_layout, buf.as_mut_ptr(), buf.len()) };
+    if ret < 0 {
+        return format!("{} channels", ch_layout.nb_channels);
+    }
+    unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Key-value pairs of an FFmpeg dictionary.
+pub fn dict_entries(dict: *const ffi::AVDictionary) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    let mut entry = ptr::null();
+    loop {
+        entry = unsafe { ffi::av_dict_iterate(dict, entry) };
+        if entry.is_null() {
+            break;
+        }
+        let (key, value) = unsafe { ((*entry).key, (*entry).value) };
+        entries.push(unsafe {
+            (
+                CStr::from_ptr(key).to_string_lossy().into_owned(),
+                CStr::from_ptr(value).to_string_lossy().into_owned(),
+            )
+        });
+    }
+    entries
+}
+
+/// Hex SHA-256 of a file.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("Open {} failed.", path))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Hash {} failed.", path))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect())
 }
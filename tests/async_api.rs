@@ -0,0 +1,83 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{max_diff, signal, SAMPLE_RATE};
+use rspleeter::{
+    async_api::{AsyncSeparator, STREAM_CAPACITY},
+    mock::MockBehavior,
+    processors::{StemContext, StemProcessors},
+    splitter::{Separator, SpleeterModelInfo},
+    utils::AudioData,
+};
+use tokio::time;
+
+/// One job at a time, so a job still holding its slot blocks the next one.
+fn separator(separator: Separator) -> AsyncSeparator {
+    AsyncSeparator::new(separator.with_segmenting(1, 0), 1)
+}
+
+fn mock(behavior: MockBehavior) -> Separator {
+    Separator::mock(SpleeterModelInfo::get_by_name("2stems").unwrap(), behavior)
+}
+
+fn audio(seconds: usize) -> AudioData {
+    AudioData::new(signal(SAMPLE_RATE * seconds), 2, SAMPLE_RATE)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropping_a_job_cancels_it() {
+    // 100 segments of 100 ms, far longer than the timeouts below.
+    let separator = separator(mock(MockBehavior::Delay(Duration::from_millis(100))));
+    let dropped = time::timeout(Duration::from_millis(300), separator.separate(audio(100))).await;
+    assert!(dropped.is_err());
+
+    // The slot is only released once the dropped job stopped.
+    let stems = time::timeout(Duration::from_secs(3), separator.separate(audio(1)))
+        .await
+        .expect("dropped job kept running")
+        .unwrap();
+    assert_eq!(stems.len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_waits_for_the_consumer() {
+    let separator = separator(mock(MockBehavior::Identity));
+    let input = audio(10);
+    let samples = input.samples.clone();
+    let mut stream = separator.separate_stream(input);
+    let mut streamed = vec![vec![]; 2];
+    let mut receive = |stems: Vec<Vec<f32>>| {
+        for (stem, segment) in streamed.iter_mut().zip(stems) {
+            stem.extend(segment);
+        }
+    };
+    // The stream job holds the slot from now on.
+    receive(stream.recv().await.unwrap().unwrap());
+
+    // Unconsumed segments fill the channel, then the job waits, keeping its
+    // slot.
+    let blocked = time::timeout(Duration::from_millis(300), separator.separate(audio(1))).await;
+    assert!(blocked.is_err());
+    assert_eq!(stream.len(), STREAM_CAPACITY);
+
+    while let Some(stems) = stream.recv().await {
+        receive(stems.unwrap());
+    }
+    for stem in &streamed {
+        assert!(max_diff(stem, &samples) < 1e-6);
+    }
+    separator.separate(audio(1)).await.unwrap();
+}
+
+#[tokio::test]
+#[should_panic(expected = "processor panicked")]
+async fn panics_reach_the_caller() {
+    let mut processors = StemProcessors::new();
+    processors.add(
+        "vocals",
+        |_: &mut [f32], _: &StemContext| -> anyhow::Result<()> { panic!("processor panicked") },
+    );
+    let separator = separator(mock(MockBehavior::Identity).with_processors(processors));
+    let _ = separator.separate(audio(1)).await;
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use std::{fs, process::Command};
+
+use common::{max_diff, read_audio, signal, temp_dir, write_wav, SAMPLE_RATE};
+use rspleeter::mock::MOCK_BACKEND_ENV;
+
+fn rspleeter(dir: &camino::Utf8Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rspleeter"));
+    // Keep the user's config out of the way.
+    command
+        .env("XDG_CONFIG_HOME", dir)
+        .env(MOCK_BACKEND_ENV, "gains=1,0.5");
+    command
+}
+
+#[test]
+fn splits_wav_with_manifest() {
+    let dir = temp_dir("cli-split");
+    let input = dir.join("input.wav");
+    let samples: Vec<f32> = signal(SAMPLE_RATE * 3)
+        .iter()
+        .map(|x| (x * 32767.).round() / 32767.)
+        .collect();
+    write_wav(&input, &samples);
+    let out_dir = dir.join("out");
+
+    let status = rspleeter(&dir)
+        .args([
+            input.as_str(),
+            out_dir.as_str(),
+            "--manifest",
+            "--dither",
+            "none",
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let vocals = read_audio(&out_dir.join("vocals.wav"));
+    assert!(max_diff(&vocals, &samples) < 1e-3);
+    let accompaniment = read_audio(&out_dir.join("accompaniment.wav"));
+    let half: Vec<f32> = samples.iter().map(|x| x / 2.).collect();
+    assert!(max_diff(&accompaniment, &half) < 1e-3);
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(out_dir.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(manifest["model"], "2stems");
+    assert_eq!(manifest["inputs"][0]["status"], "processed");
+    assert_eq!(manifest["inputs"][0]["stems"].as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn missing_input_exits_with_input_error() {
+    let dir = temp_dir("cli-missing");
+    let output = rspleeter(&dir)
+        .args([dir.join("missing.wav").as_str(), dir.join("out").as_str()])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn template_leaving_out_dir_is_rejected() {
+    let dir = temp_dir("cli-template");
+    let input = dir.join("input.wav");
+    write_wav(&input, &signal(SAMPLE_RATE));
+    let out_dir = dir.join("out");
+
+    for template in [
+        "../escaped/{track}.{ext}",
+        "/tmp/{track}.{ext}",
+        "stems.{ext}",
+    ] {
+        let output = rspleeter(&dir)
+            .args([
+                input.as_str(),
+                out_dir.as_str(),
+                "--output-template",
+                template,
+            ])
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{}", template);
+    }
+    assert!(!dir.join("escaped").exists());
+    assert!(fs::read_dir(&out_dir).map_or(true, |mut x| x.next().is_none()));
+}
+
+#[test]
+fn colliding_batch_inputs_are_rejected() {
+    let dir = temp_dir("cli-batch-collision");
+    // Different contents, so that their hashes differ.
+    for (seconds, sub_dir) in [(1, "a"), (2, "b")] {
+        fs::create_dir_all(dir.join(sub_dir)).unwrap();
+        write_wav(
+            &dir.join(sub_dir).join("song.wav"),
+            &signal(SAMPLE_RATE * seconds),
+        );
+    }
+    let out_dir = dir.join("out");
+
+    // Both default to `song/<track>.wav`.
+    let inputs = [dir.join("a/song.wav"), dir.join("b/song.wav")];
+    let output = rspleeter(&dir)
+        .args([inputs[0].as_str(), inputs[1].as_str(), out_dir.as_str()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(!out_dir.join("song").exists());
+
+    let status = rspleeter(&dir)
+        .args([inputs[0].as_str(), inputs[1].as_str(), out_dir.as_str()])
+        .args(["--output-template", "{input_stem}-{hash}/{track}.{ext}"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(fs::read_dir(&out_dir).unwrap().count(), 2);
+}
@@ -0,0 +1,80 @@
+//! Helpers shared by the tests, which run on the mock backend.
+#![allow(dead_code)]
+
+use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
+use std::{fs, process};
+
+use rspleeter::{
+    control::Control,
+    decode,
+    utils::{AudioData, AudioInfo},
+};
+
+pub const SAMPLE_RATE: usize = 44100;
+
+/// Deterministic stereo test signal, chords on the left, noise on the right.
+pub fn signal(frames: usize) -> Vec<f32> {
+    let mut seed = 1u32;
+    (0..frames)
+        .flat_map(|i| {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            let left = [220., 277.2, 329.6]
+                .iter()
+                .map(|f| (2. * std::f32::consts::PI * f * t).sin() / 4.)
+                .sum::<f32>();
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            let right = (seed >> 8) as f32 / (1 << 24) as f32 - 0.5;
+            [left, right]
+        })
+        .collect()
+}
+
+/// Empty directory unique to this process and `name`.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from_path_buf(std::env::temp_dir())
+        .unwrap()
+        .join(format!("rspleeter-test-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write interleaved stereo `samples` as a 16-bit WAV file.
+pub fn write_wav(path: &Path, samples: &[f32]) {
+    let data_len = (samples.len() * 2) as u32;
+    let mut wav = Vec::new();
+    wav.extend(b"RIFF");
+    wav.extend((36 + data_len).to_le_bytes());
+    wav.extend(b"WAVEfmt ");
+    wav.extend(16u32.to_le_bytes());
+    // PCM, 2 channels.
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(2u16.to_le_bytes());
+    wav.extend((SAMPLE_RATE as u32).to_le_bytes());
+    wav.extend((SAMPLE_RATE as u32 * 4).to_le_bytes());
+    wav.extend(4u16.to_le_bytes());
+    wav.extend(16u16.to_le_bytes());
+    wav.extend(b"data");
+    wav.extend(data_len.to_le_bytes());
+    for x in samples {
+        wav.extend(((x.clamp(-1., 1.) * 32767.).round() as i16).to_le_bytes());
+    }
+    fs::write(path, wav).unwrap();
+}
+
+/// Decode `path` into interleaved stereo samples at `SAMPLE_RATE`.
+pub fn read_audio(path: &Path) -> Vec<f32> {
+    let pcm_audio_info = AudioInfo::new_pcm(SAMPLE_RATE);
+    let (_, pcm_data) =
+        decode::decode_audio(path, &pcm_audio_info, false, &Control::default()).unwrap();
+    AudioData::from_pcm(&pcm_data, &pcm_audio_info).samples
+}
+
+/// Largest absolute difference, `a` and `b` must have the same length.
+pub fn max_diff(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0., f32::max)
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use common::{max_diff, signal, SAMPLE_RATE};
+use rspleeter::{
+    control::Control,
+    mock::MockBehavior,
+    processors::{ProcessorConfig, StemContext, StemProcessors},
+    splitter::{Separator, SpleeterModelInfo},
+};
+
+fn separate(separator: &Separator, samples: &[f32]) -> Vec<Vec<f32>> {
+    separator
+        .separate_interleaved(samples, 2, SAMPLE_RATE, &Control::default())
+        .unwrap()
+}
+
+fn model(name: &str) -> &'static SpleeterModelInfo {
+    SpleeterModelInfo::get_by_name(name).unwrap()
+}
+
+#[test]
+fn identity_across_segments() {
+    let samples = signal(SAMPLE_RATE * 7 + 321);
+    // (2, 5) is clamped to (2, 1).
+    for (length, overlap) in [(30, 5), (2, 1), (1, 0), (3, 2), (2, 5)] {
+        let separator = Separator::mock(model("2stems"), MockBehavior::Identity)
+            .with_segmenting(length, overlap);
+        for stem in separate(&separator, &samples) {
+            assert!(
+                max_diff(&stem, &samples) < 1e-6,
+                "segmenting {}/{}",
+                length,
+                overlap
+            );
+        }
+    }
+}
+
+#[test]
+fn gains_scale_stems() {
+    let samples = signal(SAMPLE_RATE * 3);
+    let separator = Separator::mock(model("4stems"), MockBehavior::Gains(vec![1., 0.5, -2.]))
+        .with_segmenting(1, 0);
+    let stems = separate(&separator, &samples);
+    assert_eq!(stems.len(), 4);
+    for (stem, gain) in stems.iter().zip([1., 0.5, -2., 0.]) {
+        let expected: Vec<f32> = samples.iter().map(|x| x * gain).collect();
+        assert!(max_diff(stem, &expected) < 1e-6);
+    }
+}
+
+#[test]
+fn bands_sum_to_input() {
+    let samples = signal(SAMPLE_RATE * 4);
+    let separator = Separator::mock(
+        model("5stems"),
+        MockBehavior::Bands(vec![100., 500., 2000., 8000.]),
+    )
+    .with_segmenting(2, 1);
+    let stems = separate(&separator, &samples);
+    let sum: Vec<f32> = (0..samples.len())
+        .map(|i| stems.iter().map(|stem| stem[i]).sum())
+        .collect();
+    assert!(max_diff(&sum, &samples) < 1e-5);
+    // Noise on the right channel reaches the highest band.
+    assert!(stems[4].iter().skip(1).step_by(2).any(|x| x.abs() > 0.01));
+}
+
+#[test]
+fn behavior_round_trips() {
+    for s in ["identity", "gains=1,0.5", "bands=500,4000", "delay=50"] {
+        assert_eq!(s.parse::<MockBehavior>().unwrap().to_string(), s);
+    }
+    assert!("bands=4000,500".parse::<MockBehavior>().is_err());
+    assert!("gains=loud".parse::<MockBehavior>().is_err());
+}
+
+#[test]
+fn processors_run_on_stems() {
+    let samples = signal(SAMPLE_RATE * 2);
+    let mut processors = StemProcessors::new();
+    processors.add("vocals", ProcessorConfig::Gain { db: -6.0206 });
+    processors.add("accompaniment", |samples: &mut [f32], _: &StemContext| {
+        samples.fill(0.);
+        Ok(())
+    });
+    let separator =
+        Separator::mock(model("2stems"), MockBehavior::Identity).with_processors(processors);
+    let stems = separate(&separator, &samples);
+    let half: Vec<f32> = samples.iter().map(|x| x / 2.).collect();
+    assert!(max_diff(&stems[0], &half) < 1e-4);
+    assert!(stems[1].iter().all(|x| *x == 0.));
+}
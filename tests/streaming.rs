@@ -0,0 +1,82 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::{max_diff, signal, SAMPLE_RATE};
+use rspleeter::{
+    control::Control,
+    mock::MockBehavior,
+    splitter::{Separator, SpleeterModelInfo},
+    streaming::StreamingSeparator,
+};
+
+fn separator() -> Arc<Separator> {
+    let model_info = SpleeterModelInfo::get_by_name("4stems").unwrap();
+    // Band filters restart at every segment, so segmenting shows in the
+    // output unless the streaming and offline segments are the same.
+    let behavior = MockBehavior::Bands(vec![200., 1000., 5000.]);
+    Arc::new(Separator::mock(model_info, behavior).with_segmenting(2, 1))
+}
+
+/// Stems of `samples` pushed in blocks of `block_frames`, without the
+/// leading silence.
+fn stream(
+    streaming: &mut StreamingSeparator,
+    samples: &[f32],
+    block_frames: usize,
+) -> Vec<Vec<f32>> {
+    let latency = streaming.latency();
+    let mut streamed = vec![vec![]; 4];
+    for block in samples.chunks(2 * block_frames) {
+        for (stem, block) in streamed.iter_mut().zip(streaming.push(block).unwrap()) {
+            stem.extend(block);
+        }
+    }
+    for (stem, block) in streamed.iter_mut().zip(streaming.finish().unwrap()) {
+        stem.extend(block);
+    }
+    streamed
+        .into_iter()
+        .map(|stem| {
+            assert!(stem[..2 * latency].iter().all(|x| *x == 0.));
+            stem[2 * latency..].to_vec()
+        })
+        .collect()
+}
+
+#[test]
+fn streaming_matches_offline() {
+    let separator = separator();
+    let mut streaming = StreamingSeparator::new(separator.clone(), 2, SAMPLE_RATE);
+    assert_eq!(streaming.latency(), 3 * SAMPLE_RATE);
+
+    // Whole and partial last segments, shorter than one segment.
+    for frames in [SAMPLE_RATE * 7 + 1234, SAMPLE_RATE * 6, SAMPLE_RATE + 5] {
+        let samples = signal(frames);
+        let offline = separator
+            .separate_interleaved(&samples, 2, SAMPLE_RATE, &Control::default())
+            .unwrap();
+        // Odd block sizes straddle segment boundaries.
+        for block_frames in [1021, SAMPLE_RATE * 5] {
+            let streamed = stream(&mut streaming, &samples, block_frames);
+            for (streamed, offline) in streamed.iter().zip(&offline) {
+                assert!(max_diff(streamed, offline) < 1e-5);
+            }
+        }
+    }
+}
+
+#[test]
+fn stems_come_out_latency_behind() {
+    let separator = separator();
+    let mut streaming = StreamingSeparator::new(separator, 2, SAMPLE_RATE);
+    let samples = signal(SAMPLE_RATE * 10);
+    for block in samples.chunks(2 * 4410) {
+        for stem in streaming.push(block).unwrap() {
+            assert_eq!(stem.len(), block.len());
+        }
+    }
+    for stem in streaming.finish().unwrap() {
+        assert_eq!(stem.len(), 2 * streaming.latency());
+    }
+}
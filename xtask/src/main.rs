@@ -67,9 +67,9 @@ fn build_ffmpeg(ffmpeg_path: &Path, ffmpeg_build_path: &Path) -> Result<()> {
         .arg(format!("--prefix={}", ffmpeg_build_path))
         .arg("--disable-hwaccels")
         .arg("--disable-encoders")
-        .arg("--enable-encoder=flac,aac,opus,pcm_f32le,alac,wmav2,libmp3lame")
+        .arg("--enable-encoder=flac,aac,opus,pcm_f32le,pcm_s16le,pcm_s24le,pcm_s32le,alac,wmav2,libmp3lame")
         .arg("--disable-decoders")
-        .arg("--enable-decoder=flac,aac,opus,pcm_f32le,alac,wmav2,mp3")
+        .arg("--enable-decoder=flac,aac,opus,pcm_f32le,pcm_s16le,pcm_s24le,pcm_s32le,alac,wmav2,mp3")
         .arg("--disable-parsers")
         .arg("--disable-protocols")
         .arg("--disable-bsfs")